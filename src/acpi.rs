@@ -0,0 +1,235 @@
+use alloc::vec::Vec;
+use core::{mem, ptr, slice};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// I/O APIC 的信息，来自 MADT。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// First global system interrupt handled by this I/O APIC.
+    pub gsi_base: u32,
+}
+
+/// 一个 ISA 中断被改接到了另一个全局系统中断 (GSI) 上。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub irq: u8,
+    pub gsi: u32,
+    /// MPS INTI flags: bits 0-1 polarity, bits 2-3 trigger mode.
+    pub flags: u16,
+}
+
+impl InterruptOverride {
+    pub fn active_low(&self) -> bool {
+        self.flags & 0b11 == 0b11
+    }
+
+    pub fn level_triggered(&self) -> bool {
+        (self.flags >> 2) & 0b11 == 0b11
+    }
+}
+
+/// MADT (Multiple APIC Description Table) 中内核关心的部分。
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    /// The system also has dual 8259 PICs.
+    pub pcat_compat: bool,
+    pub local_apic_ids: Vec<u8>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+impl Madt {
+    /// 返回 ISA 中断 `irq` 对应的 GSI 和改接信息 (如果有)。
+    pub fn isa_irq(&self, irq: u8) -> (u32, Option<InterruptOverride>) {
+        match self.overrides.iter().find(|o| o.irq == irq) {
+            Some(o) => (o.gsi, Some(*o)),
+            None => (irq as u32, None),
+        }
+    }
+}
+
+/// HPET 表中内核关心的部分。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hpet {
+    /// Physical address of the 1 KiB register block.
+    pub address: PhysAddr,
+    pub number: u8,
+    /// Minimum main counter ticks for a periodic timer.
+    pub minimum_tick: u16,
+}
+
+// 所有 ACPI 表共用的表头
+#[repr(C, packed)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+unsafe fn read<T>(phys: u64, physical_memory_offset: VirtAddr) -> T {
+    ptr::read_unaligned((physical_memory_offset + phys).as_ptr::<T>())
+}
+
+unsafe fn bytes(phys: u64, len: usize, physical_memory_offset: VirtAddr) -> &'static [u8] {
+    slice::from_raw_parts((physical_memory_offset + phys).as_ptr::<u8>(), len)
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+/// 在 BIOS 内存区域里查找 RSDP: EBDA 的第一个 KiB，然后是 0xE0000-0xFFFFF。
+///
+/// 这个函数是不安全的，因为调用者必须保证完整的物理内存被映射在
+/// `physical_memory_offset` 处。
+pub unsafe fn find_rsdp(physical_memory_offset: VirtAddr) -> Option<PhysAddr> {
+    let ebda = (read::<u16>(0x40e, physical_memory_offset) as u64) << 4;
+    let areas = [(ebda, ebda + 1024), (0xe0000, 0x100000)];
+    for &(start, end) in areas.iter().filter(|&&(start, _)| start != 0) {
+        for addr in (start..end).step_by(16) {
+            let candidate = bytes(addr, 20, physical_memory_offset);
+            if &candidate[..8] == b"RSD PTR " && checksum_ok(candidate) {
+                return Some(PhysAddr::new(addr));
+            }
+        }
+    }
+    None
+}
+
+/// 通过 RSDT/XSDT 查找签名为 `signature` 的 ACPI 表，并检查校验和。
+///
+/// 这个函数是不安全的，原因同 `find_rsdp`。
+pub unsafe fn find_table(
+    signature: &[u8; 4],
+    physical_memory_offset: VirtAddr,
+) -> Option<PhysAddr> {
+    let rsdp = find_rsdp(physical_memory_offset)?.as_u64();
+    let revision: u8 = read(rsdp + 15, physical_memory_offset);
+    // ACPI 2.0 以上用 64 位地址的 XSDT
+    let (root, entry_size) = if revision >= 2 {
+        (read::<u64>(rsdp + 24, physical_memory_offset), 8)
+    } else {
+        (read::<u32>(rsdp + 16, physical_memory_offset) as u64, 4)
+    };
+
+    let header: SdtHeader = read(root, physical_memory_offset);
+    let entries = (header.length as usize - mem::size_of::<SdtHeader>()) / entry_size;
+    for i in 0..entries {
+        let entry = root + mem::size_of::<SdtHeader>() as u64 + (i * entry_size) as u64;
+        let table = if entry_size == 8 {
+            read::<u64>(entry, physical_memory_offset)
+        } else {
+            read::<u32>(entry, physical_memory_offset) as u64
+        };
+        let header: SdtHeader = read(table, physical_memory_offset);
+        if &header.signature == signature
+            && checksum_ok(bytes(table, header.length as usize, physical_memory_offset))
+        {
+            return Some(PhysAddr::new(table));
+        }
+    }
+    None
+}
+
+/// 查找并解析 MADT，没有 ACPI 或者没有 MADT 时返回 `None`。
+///
+/// 这个函数是不安全的，原因同 `find_rsdp`。
+pub unsafe fn madt(physical_memory_offset: VirtAddr) -> Option<Madt> {
+    let table = find_table(b"APIC", physical_memory_offset)?.as_u64();
+    let header: SdtHeader = read(table, physical_memory_offset);
+    let header_size = mem::size_of::<SdtHeader>() as u64;
+
+    let mut madt = Madt {
+        local_apic_address: PhysAddr::new(
+            read::<u32>(table + header_size, physical_memory_offset) as u64
+        ),
+        pcat_compat: read::<u32>(table + header_size + 4, physical_memory_offset) & 1 != 0,
+        local_apic_ids: Vec::new(),
+        io_apics: Vec::new(),
+        overrides: Vec::new(),
+    };
+
+    let end = table + header.length as u64;
+    let mut entry = table + header_size + 8;
+    while entry + 2 <= end {
+        let entry_type: u8 = read(entry, physical_memory_offset);
+        let length: u8 = read(entry + 1, physical_memory_offset);
+        if length < 2 {
+            break;
+        }
+        match entry_type {
+            // Processor Local APIC: processor id, APIC id, flags (bit 0 = enabled)
+            0 => {
+                let flags: u32 = read(entry + 4, physical_memory_offset);
+                if flags & 1 != 0 {
+                    madt.local_apic_ids
+                        .push(read(entry + 3, physical_memory_offset));
+                }
+            }
+            1 => madt.io_apics.push(IoApic {
+                id: read(entry + 2, physical_memory_offset),
+                address: PhysAddr::new(read::<u32>(entry + 4, physical_memory_offset) as u64),
+                gsi_base: read(entry + 8, physical_memory_offset),
+            }),
+            // 只关心 ISA 总线 (bus 0) 的改接
+            2 if read::<u8>(entry + 2, physical_memory_offset) == 0 => {
+                madt.overrides.push(InterruptOverride {
+                    irq: read(entry + 3, physical_memory_offset),
+                    gsi: read(entry + 4, physical_memory_offset),
+                    flags: read(entry + 8, physical_memory_offset),
+                })
+            }
+            // Local APIC Address Override
+            5 => {
+                madt.local_apic_address = PhysAddr::new(read(entry + 4, physical_memory_offset));
+            }
+            _ => {}
+        }
+        entry += length as u64;
+    }
+    Some(madt)
+}
+
+/// 查找 HPET 表，没有 HPET 或者寄存器不在内存地址空间时返回 `None`。
+///
+/// 这个函数是不安全的，原因同 `find_rsdp`。
+pub unsafe fn hpet(physical_memory_offset: VirtAddr) -> Option<Hpet> {
+    let table = find_table(b"HPET", physical_memory_offset)?.as_u64();
+    let base = table + mem::size_of::<SdtHeader>() as u64;
+    // 基地址是一个 Generic Address Structure，address space 0 表示内存
+    if read::<u8>(base + 4, physical_memory_offset) != 0 {
+        return None;
+    }
+    Some(Hpet {
+        address: PhysAddr::new(read(base + 8, physical_memory_offset)),
+        number: read(base + 16, physical_memory_offset),
+        minimum_tick: read(base + 17, physical_memory_offset),
+    })
+}
+
+/// FADT 中 CMOS 世纪寄存器的索引，没有 FADT 或者 FADT 没有提供世纪寄存器时
+/// 返回 `None`。
+///
+/// 这个函数是不安全的，原因同 `find_rsdp`。
+pub unsafe fn century_register(physical_memory_offset: VirtAddr) -> Option<u8> {
+    // century 字段在偏移 108 处，ACPI 1.0 之前的短表没有这个字段，0 表示不支持
+    const CENTURY_OFFSET: u64 = 108;
+    let table = find_table(b"FACP", physical_memory_offset)?.as_u64();
+    let header: SdtHeader = read(table, physical_memory_offset);
+    if (header.length as u64) <= CENTURY_OFFSET {
+        return None;
+    }
+    match read::<u8>(table + CENTURY_OFFSET, physical_memory_offset) {
+        0 => None,
+        register => Some(register),
+    }
+}
@@ -1,14 +1,18 @@
 use alloc::alloc::{GlobalAlloc, Layout};
+use core::{
+    fmt, ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 use x86_64::{
-    structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTable, PageTableFlags, Size4KiB,
-    },
+    structures::paging::{mapper::MapToError, Page, PageTableFlags, Size4KiB},
     VirtAddr,
 };
 
-use self::linked_list::LinkedListAllocator;
+use crate::memory;
 
 pub mod bump;
+pub mod debug;
+pub mod fixed_size_block;
 pub mod linked_list;
 
 pub struct Locked<T> {
@@ -28,53 +32,383 @@ impl<T> Locked<T> {
 }
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB, mapped by init_heap
+/// Default ceiling the heap may grow to, see `init_heap_with_limit`.
+pub const HEAP_MAX_SIZE: usize = 16 * 1024 * 1024; // 16 MiB
+/// Minimum number of bytes mapped each time the heap grows.
+const HEAP_GROW_STEP: usize = 64 * 1024; // 64 KiB
 
-// 1. use crate linked_list_allocator
-// use linked_list_allocator::LockedHeap;
+const HEAP_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_EXECUTE);
+
+/// An allocator that can manage the kernel heap.
+pub trait HeapBackend: GlobalAlloc {
+    /// Initialize the allocator with the given, already mapped, heap bounds.
+    unsafe fn init(&self, heap_start: usize, heap_size: usize);
+
+    /// Hands the `by` bytes directly after the current heap end to the
+    /// allocator. The caller must have mapped them.
+    unsafe fn extend(&self, by: usize);
+
+    /// Called by `GrowableHeap` after the last live allocation was freed.
+    /// Backends that cannot reuse single frees start over here.
+    unsafe fn reset(&self) {}
+
+    /// Describes the free memory the allocator currently manages, or `None`
+    /// if the backend cannot inspect it.
+    fn free_regions(&self) -> Option<FreeRegions>;
+}
+
+/// Free memory as seen by a heap backend.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FreeRegions {
+    /// Number of separate free regions.
+    pub count: usize,
+    /// Size of the largest free region in bytes.
+    pub largest: usize,
+}
+
+/// A snapshot of the kernel heap usage, see `heap_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Number of bytes currently mapped for the heap.
+    pub heap_size: usize,
+    /// Sum of the requested sizes of all live allocations.
+    pub bytes_in_use: usize,
+    /// Highest value `bytes_in_use` has reached.
+    pub peak_usage: usize,
+    pub free_regions: Option<FreeRegions>,
+    pub allocations: usize,
+    pub deallocations: usize,
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "heap size:      {} bytes", self.heap_size)?;
+        writeln!(f, "in use:         {} bytes", self.bytes_in_use)?;
+        writeln!(f, "peak usage:     {} bytes", self.peak_usage)?;
+        match self.free_regions {
+            Some(free) => {
+                writeln!(f, "free regions:   {}", free.count)?;
+                writeln!(f, "largest free:   {} bytes", free.largest)?;
+            }
+            None => writeln!(f, "free regions:   unknown")?,
+        }
+        writeln!(f, "allocations:    {}", self.allocations)?;
+        write!(f, "deallocations:  {}", self.deallocations)
+    }
+}
+
+/// Usage counters shared by every backend, updated without taking the
+/// backend lock.
+struct HeapCounters {
+    bytes_in_use: AtomicUsize,
+    peak_usage: AtomicUsize,
+    allocations: AtomicUsize,
+    deallocations: AtomicUsize,
+}
+
+impl HeapCounters {
+    const fn new() -> Self {
+        Self {
+            bytes_in_use: AtomicUsize::new(0),
+            peak_usage: AtomicUsize::new(0),
+            allocations: AtomicUsize::new(0),
+            deallocations: AtomicUsize::new(0),
+        }
+    }
 
-// #[global_allocator]
-// static ALLOCATOR: LockedHeap = LockedHeap::empty();
+    fn record_alloc(&self, size: usize) {
+        let in_use = self.bytes_in_use.fetch_add(size, Ordering::Relaxed) + size;
+        self.peak_usage.fetch_max(in_use, Ordering::Relaxed);
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns true if this freed the last live allocation.
+    fn record_dealloc(&self, size: usize) -> bool {
+        self.bytes_in_use.fetch_sub(size, Ordering::Relaxed);
+        let deallocations = self.deallocations.fetch_add(1, Ordering::Relaxed) + 1;
+        deallocations == self.allocations.load(Ordering::Relaxed)
+    }
+
+    // realloc 只改变大小，不算一次分配和释放
+    fn record_resize(&self, old_size: usize, new_size: usize) {
+        if new_size >= old_size {
+            let grown = new_size - old_size;
+            let in_use = self.bytes_in_use.fetch_add(grown, Ordering::Relaxed) + grown;
+            self.peak_usage.fetch_max(in_use, Ordering::Relaxed);
+        } else {
+            self.bytes_in_use
+                .fetch_sub(old_size - new_size, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Wraps a heap backend and maps more pages past the end of the heap when
+/// an allocation cannot be satisfied.
+pub struct GrowableHeap<A> {
+    backend: A,
+    grower: spin::Mutex<Option<HeapGrower>>,
+    counters: HeapCounters,
+}
+
+/// Where the heap currently ends and how far it may grow.
+struct HeapGrower {
+    heap_end: usize,
+    max_end: usize,
+}
+
+impl<A: HeapBackend> GrowableHeap<A> {
+    pub const fn new(backend: A) -> Self {
+        Self {
+            backend,
+            grower: spin::Mutex::new(None),
+            counters: HeapCounters::new(),
+        }
+    }
+
+    pub fn backend(&self) -> &A {
+        &self.backend
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        let heap_size = self
+            .grower
+            .lock()
+            .as_ref()
+            .map_or(0, |grower| grower.heap_end - HEAP_START);
+        HeapStats {
+            heap_size,
+            bytes_in_use: self.counters.bytes_in_use.load(Ordering::Relaxed),
+            peak_usage: self.counters.peak_usage.load(Ordering::Relaxed),
+            free_regions: self.backend.free_regions(),
+            allocations: self.counters.allocations.load(Ordering::Relaxed),
+            deallocations: self.counters.deallocations.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Maps at least `min_size` more bytes after the heap end and gives them
+    /// to the backend.
+    ///
+    /// Returns false if the heap ceiling is reached or no frames are left.
+    fn grow(&self, min_size: usize) -> bool {
+        let mut grower = self.grower.lock();
+        let grower = match grower.as_mut() {
+            Some(grower) => grower,
+            None => return false,
+        };
+
+        let remaining = grower.max_end - grower.heap_end;
+        if min_size > remaining {
+            return false;
+        }
+        let size = align_up(
+            min_size.max(HEAP_GROW_STEP),
+            Page::<Size4KiB>::SIZE as usize,
+        )
+        .min(remaining);
+
+        // 逐页映射 (对齐时用 2 MiB 大页)，映射失败之前已经映射好的页仍然交给分配器
+        let mut mapped = 0;
+        let complete = memory::with_kernel_memory(|memory| {
+            while mapped < size {
+                let addr = VirtAddr::new((grower.heap_end + mapped) as u64);
+                match memory.map_anonymous_page(addr, (size - mapped) as u64, HEAP_FLAGS) {
+                    Ok(page_size) => mapped += page_size as usize,
+                    Err(_) => return false,
+                }
+            }
+            true
+        });
+
+        if mapped > 0 {
+            grower.heap_end += mapped;
+            unsafe { self.backend.extend(mapped) };
+        }
+        complete == Some(true)
+    }
+}
+
+unsafe impl<A: HeapBackend> GlobalAlloc for GrowableHeap<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let mut ptr = self.backend.alloc(layout);
+        if ptr.is_null() {
+            // out of memory -> map more heap pages and try again
+            ptr = match layout.size().checked_add(layout.align()) {
+                Some(min_size) if self.grow(min_size) => self.backend.alloc(layout),
+                _ => ptr::null_mut(),
+            };
+        }
+
+        if !ptr.is_null() {
+            self.counters.record_alloc(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let last = self.counters.record_dealloc(layout.size());
+        self.backend.dealloc(ptr, layout);
+        if last {
+            self.backend.reset();
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // forward to the backend so that it can resize in place
+        let mut new_ptr = self.backend.realloc(ptr, layout, new_size);
+        if new_ptr.is_null() {
+            new_ptr = match new_size.checked_add(layout.align()) {
+                Some(min_size) if self.grow(min_size) => {
+                    self.backend.realloc(ptr, layout, new_size)
+                }
+                _ => ptr::null_mut(),
+            };
+        }
+
+        if !new_ptr.is_null() {
+            self.counters.record_resize(layout.size(), new_size);
+        }
+        new_ptr
+    }
+}
+
+impl HeapBackend for linked_list_allocator::LockedHeap {
+    unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        self.lock().init(heap_start, heap_size)
+    }
+
+    unsafe fn extend(&self, by: usize) {
+        self.lock().extend(by)
+    }
+
+    fn free_regions(&self) -> Option<FreeRegions> {
+        // the external crate does not expose its hole list
+        None
+    }
+}
+
+// 全局分配器在编译期通过 cargo feature 选择，同时只能开启一个
+// cargo build --no-default-features --features alloc-bump
+#[cfg(any(
+    all(feature = "alloc-bump", feature = "alloc-linked-list"),
+    all(feature = "alloc-bump", feature = "alloc-fixed-size-block"),
+    all(feature = "alloc-bump", feature = "alloc-external"),
+    all(feature = "alloc-linked-list", feature = "alloc-fixed-size-block"),
+    all(feature = "alloc-linked-list", feature = "alloc-external"),
+    all(feature = "alloc-fixed-size-block", feature = "alloc-external"),
+))]
+compile_error!("only one `alloc-*` feature can be enabled at a time");
+
+#[cfg(not(any(
+    feature = "alloc-bump",
+    feature = "alloc-linked-list",
+    feature = "alloc-fixed-size-block",
+    feature = "alloc-external",
+)))]
+compile_error!("one of the `alloc-*` features must be enabled to pick a global allocator");
+
+// 1. use crate linked_list_allocator
+#[cfg(feature = "alloc-external")]
+type Backend = linked_list_allocator::LockedHeap;
+#[cfg(feature = "alloc-external")]
+const fn backend() -> Backend {
+    linked_list_allocator::LockedHeap::empty()
+}
 
 // 2. use bump allocator
-// use bump::BumpAllocator;
-// #[global_allocator]
-// static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
+#[cfg(feature = "alloc-bump")]
+type Backend = Locked<bump::BumpAllocator>;
+#[cfg(feature = "alloc-bump")]
+const fn backend() -> Backend {
+    Locked::new(bump::BumpAllocator::new())
+}
 
 // 3. locally impl LinkedListAllocator
+#[cfg(feature = "alloc-linked-list")]
+type Backend = Locked<linked_list::LinkedListAllocator>;
+#[cfg(feature = "alloc-linked-list")]
+const fn backend() -> Backend {
+    Locked::new(linked_list::LinkedListAllocator::new())
+}
+
+// 4. fixed size block allocator, falls back to LinkedListAllocator for large layouts
+#[cfg(feature = "alloc-fixed-size-block")]
+type Backend = Locked<fixed_size_block::FixedSizeBlockAllocator>;
+#[cfg(feature = "alloc-fixed-size-block")]
+const fn backend() -> Backend {
+    Locked::new(fixed_size_block::FixedSizeBlockAllocator::new())
+}
+
+#[cfg(not(feature = "alloc-debug"))]
+#[global_allocator]
+static ALLOCATOR: GrowableHeap<Backend> = GrowableHeap::new(backend());
+
+// alloc-debug 可以和任意一个分配器组合，检查越界写、重复释放等堆错误
+#[cfg(feature = "alloc-debug")]
 #[global_allocator]
-static ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
+static ALLOCATOR: GrowableHeap<debug::DebugAllocator<Backend>> =
+    GrowableHeap::new(debug::DebugAllocator::new(backend()));
 
 // for map the heap pages to physical memory
-pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
+pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
+    init_heap_with_limit(HEAP_MAX_SIZE)
+}
+
+/// Maps the initial `HEAP_SIZE` bytes of the heap, which may later grow up
+/// to `max_size` bytes.
+///
+/// Uses the kernel page table and frame allocator, so
+/// `memory::init_kernel_memory` must be called first.
+pub fn init_heap_with_limit(max_size: usize) -> Result<(), MapToError<Size4KiB>> {
+    assert!(
+        max_size >= HEAP_SIZE,
+        "heap ceiling below initial heap size"
+    );
+    assert_eq!(max_size % Page::<Size4KiB>::SIZE as usize, 0);
+
+    // the heap grows while the kernel memory lock is held, so every address
+    // space has to share its page tables from the start
+    memory::address_space::preallocate_kernel_range(
+        VirtAddr::new(HEAP_START as u64),
+        max_size as u64,
+    );
+
     // map all heap pages to physical frames
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1 as u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
-
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() }
-    }
+    memory::with_kernel_memory(|memory| -> Result<(), MapToError<Size4KiB>> {
+        let mut mapped = 0;
+        while mapped < HEAP_SIZE as u64 {
+            let addr = VirtAddr::new(HEAP_START as u64 + mapped);
+            mapped += memory.map_anonymous_page(addr, HEAP_SIZE as u64 - mapped, HEAP_FLAGS)?;
+        }
+        Ok(())
+    })
+    .expect("kernel memory not initialized")?;
     // end
 
     // Because the init function already tries to write to the heap memory
     // must initialize the heap only after mapping the heap pages
-    unsafe { ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE) };
+    unsafe { ALLOCATOR.backend().init(HEAP_START, HEAP_SIZE) };
+
+    *ALLOCATOR.grower.lock() = Some(HeapGrower {
+        heap_end: HEAP_START + HEAP_SIZE,
+        max_end: HEAP_START + max_size,
+    });
 
     Ok(())
 }
 
+/// Returns the current usage statistics of the global allocator.
+pub fn heap_stats() -> HeapStats {
+    ALLOCATOR.stats()
+}
+
+/// Prints the current heap statistics over serial.
+pub fn print_heap_stats() {
+    crate::serial_println!("{}", heap_stats());
+}
+
 fn align_up(addr: usize, align: usize) -> usize {
     let remainder = addr % align;
     if remainder == 0 {
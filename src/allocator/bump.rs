@@ -2,13 +2,14 @@ use core::alloc::GlobalAlloc;
 use core::ptr;
 
 use super::align_up;
+use super::FreeRegions;
+use super::HeapBackend;
 use super::Locked;
 
 pub struct BumpAllocator {
     heap_start: usize,
     heap_end: usize,
     next: usize,
-    allocations: usize,
 }
 
 impl BumpAllocator {
@@ -18,7 +19,6 @@ impl BumpAllocator {
             heap_start: 0,
             heap_end: 0,
             next: 0,
-            allocations: 0,
         }
     }
 
@@ -27,6 +27,11 @@ impl BumpAllocator {
         self.heap_end = heap_start + heap_size;
         self.next = heap_start;
     }
+
+    /// Extends the heap by `by` bytes after its current end.
+    pub unsafe fn extend(&mut self, by: usize) {
+        self.heap_end += by;
+    }
 }
 
 unsafe impl GlobalAlloc for Locked<BumpAllocator> {
@@ -43,16 +48,37 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
             ptr::null_mut() // out of memory
         } else {
             bump.next = alloc_end;
-            bump.allocations += 1;
             alloc_start as *mut u8
         }
     }
 
     unsafe fn dealloc(&self, _ptr: *mut u8, _layout: core::alloc::Layout) {
+        // single frees cannot be reused, see `reset`
+    }
+}
+
+impl HeapBackend for Locked<BumpAllocator> {
+    unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        self.lock().init(heap_start, heap_size)
+    }
+
+    unsafe fn extend(&self, by: usize) {
+        self.lock().extend(by)
+    }
+
+    unsafe fn reset(&self) {
+        // every allocation is freed, start over from the heap start
         let mut bump = self.lock();
-        bump.allocations -= 1;
-        if bump.allocations == 0 {
-            bump.next = bump.heap_start;
-        }
+        bump.next = bump.heap_start;
+    }
+
+    fn free_regions(&self) -> Option<FreeRegions> {
+        // everything between `next` and the heap end is one free region
+        let bump = self.lock();
+        let free = bump.heap_end - bump.next;
+        Some(FreeRegions {
+            count: (free > 0) as usize,
+            largest: free,
+        })
     }
 }
@@ -0,0 +1,239 @@
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    fmt, mem, ptr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::{align_up, FreeRegions, HeapBackend};
+use crate::serial_println;
+
+/// Size of each red zone placed directly before and after an allocation.
+pub const RED_ZONE_SIZE: usize = 16;
+/// Byte pattern filling the red zones.
+pub const RED_ZONE_BYTE: u8 = 0xfd;
+/// Byte pattern written over freed memory.
+pub const POISON_BYTE: u8 = 0xdd;
+/// Number of freed blocks held back before they are handed to the wrapped
+/// allocator for reuse.
+pub const QUARANTINE_SIZE: usize = 8;
+
+/// Bytes at the start of every block left to the wrapped allocator, which
+/// writes its free-list node there, so that the header survives the free.
+const SCRATCH_SIZE: usize = 16;
+
+const ALLOCATED_MAGIC: usize = 0xa110_c8ed_a110_c8ed;
+const FREED_MAGIC: usize = 0xf8ee_d0ff_f8ee_d0ff;
+
+static REPORTED_ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+/// Bookkeeping stored right before the front red zone.
+#[repr(C)]
+struct Header {
+    magic: usize,
+    // distance from the start of the inner block to the user pointer
+    offset: usize,
+    size: usize,
+    align: usize,
+}
+
+/// Wraps a heap backend and checks every allocation for corruption.
+///
+/// Each block is laid out as `[scratch][header][red zone][data][red zone]`.
+/// Red zones are verified and the data is poisoned on `dealloc`, which also
+/// detects double frees and a `Layout` that differs from the allocation's.
+/// Freed blocks wait in a quarantine and the poison is verified when they
+/// leave it to be reused, which catches writes after the free.
+pub struct DebugAllocator<A> {
+    inner: A,
+    quarantine: spin::Mutex<Quarantine>,
+}
+
+/// Ring of freed user pointers that are not yet reusable.
+struct Quarantine {
+    blocks: [usize; QUARANTINE_SIZE],
+    len: usize,
+    next: usize,
+}
+
+impl Quarantine {
+    /// Adds `ptr` and returns the oldest block if the quarantine was full.
+    fn push(&mut self, ptr: usize) -> Option<usize> {
+        let evicted = if self.len == QUARANTINE_SIZE {
+            Some(self.blocks[self.next])
+        } else {
+            self.len += 1;
+            None
+        };
+        self.blocks[self.next] = ptr;
+        self.next = (self.next + 1) % QUARANTINE_SIZE;
+        evicted
+    }
+
+    /// Removes the oldest block.
+    fn pop(&mut self) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+        let oldest = (self.next + QUARANTINE_SIZE - self.len) % QUARANTINE_SIZE;
+        self.len -= 1;
+        Some(self.blocks[oldest])
+    }
+}
+
+impl<A> DebugAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        Self {
+            inner,
+            quarantine: spin::Mutex::new(Quarantine {
+                blocks: [0; QUARANTINE_SIZE],
+                len: 0,
+                next: 0,
+            }),
+        }
+    }
+}
+
+/// Returns how many heap errors the debug allocator has reported so far.
+pub fn reported_errors() -> usize {
+    REPORTED_ERRORS.load(Ordering::Relaxed)
+}
+
+fn report(ptr: *mut u8, args: fmt::Arguments) {
+    REPORTED_ERRORS.fetch_add(1, Ordering::Relaxed);
+    serial_println!("HEAP ERROR: {} at {:p}", args, ptr);
+}
+
+/// Computes the offset of the user pointer and the layout requested from the
+/// wrapped allocator.
+fn inner_layout(size: usize, align: usize) -> Option<(usize, Layout)> {
+    let align = align.max(mem::align_of::<Header>());
+    let offset = align_up(
+        SCRATCH_SIZE + mem::size_of::<Header>() + RED_ZONE_SIZE,
+        align,
+    );
+    let inner_size = offset.checked_add(size)?.checked_add(RED_ZONE_SIZE)?;
+    let layout = Layout::from_size_align(inner_size, align).ok()?;
+    Some((offset, layout))
+}
+
+unsafe fn header(ptr: *mut u8) -> *mut Header {
+    ptr.sub(RED_ZONE_SIZE + mem::size_of::<Header>()) as *mut Header
+}
+
+/// Returns the offset of the first byte in `len` bytes at `start` that is not
+/// `RED_ZONE_BYTE`.
+unsafe fn find_corruption(start: *const u8, len: usize) -> Option<usize> {
+    (0..len).find(|&i| start.add(i).read_volatile() != RED_ZONE_BYTE)
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for DebugAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let (offset, inner_layout) = match inner_layout(layout.size(), layout.align()) {
+            Some(inner) => inner,
+            None => return ptr::null_mut(),
+        };
+        let block = self.inner.alloc(inner_layout);
+        if block.is_null() {
+            return block;
+        }
+
+        let ptr = block.add(offset);
+        header(ptr).write(Header {
+            magic: ALLOCATED_MAGIC,
+            offset,
+            size: layout.size(),
+            align: layout.align(),
+        });
+        ptr::write_bytes(ptr.sub(RED_ZONE_SIZE), RED_ZONE_BYTE, RED_ZONE_SIZE);
+        ptr::write_bytes(ptr.add(layout.size()), RED_ZONE_BYTE, RED_ZONE_SIZE);
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let header = &mut *header(ptr);
+        match header.magic {
+            ALLOCATED_MAGIC => {}
+            FREED_MAGIC => {
+                // the block belongs to the wrapped allocator already, leave it alone
+                report(ptr, format_args!("double free"));
+                return;
+            }
+            _ => {
+                report(
+                    ptr,
+                    format_args!("free of invalid pointer or corrupted header"),
+                );
+                return;
+            }
+        }
+
+        if header.size != layout.size() || header.align != layout.align() {
+            report(
+                ptr,
+                format_args!(
+                    "dealloc with {:?}, allocated with size {} align {}",
+                    layout, header.size, header.align
+                ),
+            );
+        }
+        if let Some(i) = find_corruption(ptr.sub(RED_ZONE_SIZE), RED_ZONE_SIZE) {
+            report(
+                ptr,
+                format_args!(
+                    "buffer underflow, {} bytes before the start",
+                    RED_ZONE_SIZE - i
+                ),
+            );
+        }
+        if let Some(i) = find_corruption(ptr.add(header.size), RED_ZONE_SIZE) {
+            report(
+                ptr,
+                format_args!("buffer overflow at offset {} past the end", i),
+            );
+        }
+
+        ptr::write_bytes(ptr, POISON_BYTE, header.size);
+        header.magic = FREED_MAGIC;
+        let evicted = self.quarantine.lock().push(ptr as usize);
+        if let Some(evicted) = evicted {
+            self.release(evicted as *mut u8);
+        }
+    }
+}
+
+impl<A: GlobalAlloc> DebugAllocator<A> {
+    /// Checks that a quarantined block is still poisoned and hands it to the
+    /// wrapped allocator.
+    unsafe fn release(&self, ptr: *mut u8) {
+        let header = &*header(ptr);
+        if let Some(i) = (0..header.size).find(|&i| ptr.add(i).read_volatile() != POISON_BYTE) {
+            report(ptr, format_args!("use after free, written at offset {}", i));
+        }
+        // free with the recorded layout, the passed one may be wrong
+        let (offset, inner_layout) = inner_layout(header.size, header.align).unwrap();
+        debug_assert_eq!(offset, header.offset);
+        self.inner.dealloc(ptr.sub(header.offset), inner_layout);
+    }
+}
+
+impl<A: HeapBackend> HeapBackend for DebugAllocator<A> {
+    unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        self.inner.init(heap_start, heap_size)
+    }
+
+    unsafe fn extend(&self, by: usize) {
+        self.inner.extend(by)
+    }
+
+    unsafe fn reset(&self) {
+        // the wrapped allocator may reuse everything, empty the quarantine first
+        while let Some(ptr) = self.quarantine.lock().pop() {
+            self.release(ptr as *mut u8);
+        }
+        self.inner.reset()
+    }
+
+    fn free_regions(&self) -> Option<FreeRegions> {
+        self.inner.free_regions()
+    }
+}
@@ -3,7 +3,7 @@ use core::{
     mem, ptr,
 };
 
-use super::{align_up, Locked};
+use super::{align_up, FreeRegions, HeapBackend, Locked};
 
 struct ListNode {
     size: usize,
@@ -26,31 +26,81 @@ impl ListNode {
 
 pub struct LinkedListAllocator {
     head: ListNode,
+    heap_end: usize,
 }
 
 impl LinkedListAllocator {
     pub const fn new() -> Self {
         Self {
             head: ListNode::new(0),
+            heap_end: 0,
         }
     }
 
     pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        self.heap_end = heap_start + heap_size;
         self.add_free_region(heap_start, heap_size);
     }
 
-    /// Adds the given memory region to the front of the list.
+    /// Extends the heap by `by` bytes after its current end.
+    ///
+    /// The new memory is merged with the last free region if it reaches the
+    /// old heap end.
+    pub unsafe fn extend(&mut self, by: usize) {
+        let old_end = self.heap_end;
+        self.heap_end += by;
+        self.add_free_region(old_end, by);
+    }
+
+    /// Adds the given memory region to the list, keeping it sorted by address.
+    ///
+    /// The region is merged with its neighbours when they are adjacent, so
+    /// freed blocks coalesce back into larger regions.
     unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
         // ensure that the freed region is capable of holding ListNode
         assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
         assert!(size >= mem::size_of::<ListNode>());
 
-        // create a new list node and append it at the start of the list
+        // the dummy head lives inside the allocator, it must never be merged
+        let head_addr = self.head.start_addr();
+
+        // find the last node that starts before the freed region
+        let mut current = &mut self.head;
+        while current
+            .next
+            .as_ref()
+            .map_or(false, |next| next.start_addr() < addr)
+        {
+            current = current.next.as_mut().unwrap();
+        }
+
+        if current.start_addr() != head_addr && current.end_addr() == addr {
+            // previous region ends where the freed one starts -> grow it
+            current.size += size;
+            if let Some(next) = current.next.take() {
+                if current.end_addr() == next.start_addr() {
+                    // the freed region also closes the gap to the next one
+                    current.size += next.size;
+                    current.next = next.next.take();
+                } else {
+                    current.next = Some(next);
+                }
+            }
+            return;
+        }
+
         let mut node = ListNode::new(size);
-        node.next = self.head.next.take();
+        match current.next.take() {
+            Some(next) if addr + size == next.start_addr() => {
+                // freed region ends where the next one starts -> absorb it
+                node.size += next.size;
+                node.next = next.next.take();
+            }
+            next => node.next = next,
+        }
         let node_ptr = addr as *mut ListNode;
         node_ptr.write(node);
-        self.head.next = Some(&mut *node_ptr)
+        current.next = Some(&mut *node_ptr)
     }
 
     /// Looks for a free region with the given size and alignment and removes
@@ -78,6 +128,31 @@ impl LinkedListAllocator {
         None
     }
 
+    /// Removes the free region starting exactly at `addr` from the list if it
+    /// has at least `size` bytes and the rest of it can still hold a `ListNode`.
+    fn take_region_at(&mut self, addr: usize, size: usize) -> Option<&'static mut ListNode> {
+        let mut current = &mut self.head;
+        while let Some(ref mut region) = current.next {
+            if region.start_addr() > addr {
+                // the list is sorted, no region can start at addr anymore
+                return None;
+            }
+            if region.start_addr() == addr {
+                let excess_size = region.size.checked_sub(size)?;
+                if excess_size > 0 && excess_size < mem::size_of::<ListNode>() {
+                    return None;
+                }
+                let next = region.next.take();
+                let ret = current.next.take();
+                current.next = next;
+                return ret;
+            }
+            current = current.next.as_mut().unwrap();
+        }
+
+        None
+    }
+
     /// Try to use the given region for an allocation with given size and
     /// alignment.
     ///
@@ -101,6 +176,89 @@ impl LinkedListAllocator {
         // region suitable for allocation
         Ok(alloc_start)
     }
+
+    /// Allocates a block for the given layout, returning null when no free
+    /// region is large enough.
+    ///
+    /// Used directly by allocators that sit on top of this one, such as the
+    /// fixed-size block allocator's fallback path.
+    pub unsafe fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        // perform layout adjustments
+        let (size, align) = Self::size_align(layout);
+
+        if let Some((region, alloc_start)) = self.find_region(size, align) {
+            let alloc_end = alloc_start.checked_add(size).expect("overflow");
+            let excess_size = region.end_addr() - alloc_end;
+            if excess_size > 0 {
+                self.add_free_region(alloc_end, excess_size);
+            }
+            alloc_start as *mut u8
+        } else {
+            ptr::null_mut()
+        }
+    }
+
+    /// Returns a block previously handed out by `allocate` to the free list.
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        // perform layout adjustments
+        let (size, _) = Self::size_align(layout);
+
+        self.add_free_region(ptr as usize, size)
+    }
+
+    /// Resizes a block previously handed out by `allocate`.
+    ///
+    /// Grows in place when the region directly after the block is free and
+    /// shrinks in place by returning the tail to the free list. Otherwise the
+    /// block is moved. Returns null, leaving the block untouched, when no
+    /// large enough region is left.
+    pub unsafe fn reallocate(&mut self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        let (old_size, _) = Self::size_align(layout);
+        let (size, _) = Self::size_align(new_layout);
+        let addr = ptr as usize;
+
+        if size == old_size {
+            return ptr;
+        }
+
+        if size < old_size {
+            let tail_size = old_size - size;
+            if tail_size >= mem::size_of::<ListNode>() {
+                // shrink in place, the tail goes back to the free list
+                self.add_free_region(addr + size, tail_size);
+                return ptr;
+            }
+        } else if let Some(region) = self.take_region_at(addr + old_size, size - old_size) {
+            // grow in place into the following free region
+            let excess_size = region.end_addr() - (addr + size);
+            if excess_size > 0 {
+                self.add_free_region(addr + size, excess_size);
+            }
+            return ptr;
+        }
+
+        // move the block
+        let new_ptr = self.allocate(new_layout);
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.deallocate(ptr, layout);
+        }
+        new_ptr
+    }
+
+    /// Counts the free regions and finds the largest one.
+    pub fn free_regions(&self) -> FreeRegions {
+        let mut free = FreeRegions::default();
+        let mut current = &self.head;
+        while let Some(ref region) = current.next {
+            free.count += 1;
+            free.largest = free.largest.max(region.size);
+            current = region;
+        }
+        free
+    }
+
     /// Adjust the given layout so that the resulting allocated memory
     /// region is also capable of storing a `ListNode`.
     ///
@@ -117,26 +275,28 @@ impl LinkedListAllocator {
 
 unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        // perform layout adjustments
-        let (size, align) = LinkedListAllocator::size_align(layout);
-        let mut allocator = self.lock();
-
-        if let Some((region, alloc_start)) = allocator.find_region(size, align) {
-            let alloc_end = alloc_start.checked_add(size).expect("overflow");
-            let excess_size = region.end_addr() - alloc_end;
-            if excess_size > 0 {
-                allocator.add_free_region(alloc_end, excess_size);
-            }
-            alloc_start as *mut u8
-        } else {
-            ptr::null_mut()
-        }
+        self.lock().allocate(layout)
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        // perform layout adjustments
-        let (size, _) = LinkedListAllocator::size_align(layout);
+        self.lock().deallocate(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        self.lock().reallocate(ptr, layout, new_size)
+    }
+}
+
+impl HeapBackend for Locked<LinkedListAllocator> {
+    unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        self.lock().init(heap_start, heap_size)
+    }
+
+    unsafe fn extend(&self, by: usize) {
+        self.lock().extend(by)
+    }
 
-        self.lock().add_free_region(ptr as usize, size)
+    fn free_regions(&self) -> Option<FreeRegions> {
+        Some(self.lock().free_regions())
     }
 }
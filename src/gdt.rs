@@ -1,26 +1,29 @@
 // GDT是分页模式成为事实标准之前，用于内存分段的遗留结构，但它在64位模式下仍然需要处理一些事情，比如内核态/用户态的配置以及TSS载入
 // GDT是包含了程序 段信息 的结构，在分页模式成为标准前，它在旧架构下起到隔离程序执行环境的作用
+use core::ptr::{addr_of, addr_of_mut};
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::VirtAddr;
 
+use crate::memory::stack::{self, StackBounds, StackError};
+
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// Size of the guarded double fault stack in pages.
+const DOUBLE_FAULT_STACK_PAGES: u64 = 5;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        // TSS用到了分段系统 在GDT中添加一个段描述符，通过ltr 指令加上GDT序号加载我们的TSS
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            // x86 的栈内存分配是从高地址到低地址的
-            stack_end
-        };
-        tss
-    };
+// TSS用到了分段系统 在GDT中添加一个段描述符，通过ltr 指令加上GDT序号加载我们的TSS
+// 内存初始化之后还要把 IST 换成带 guard page 的栈，所以不能放在 lazy_static 里
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+/// 内存初始化之前使用的 double fault 栈，下面没有 guard page
+fn bootstrap_double_fault_stack() -> VirtAddr {
+    const STACK_SIZE: usize = 4096 * 5;
+    static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+    let stack_start = VirtAddr::from_ptr(addr_of!(STACK));
+    let stack_end = stack_start + STACK_SIZE;
+    // x86 的栈内存分配是从高地址到低地址的
+    stack_end
 }
 
 struct Selectors {
@@ -32,7 +35,7 @@ lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*addr_of!(TSS) }));
         (
             gdt,
             Selectors {
@@ -47,6 +50,10 @@ pub fn init() {
     // 修改了GDT，需要重载代码段寄存器 cs
     // 加载了包含TSS信息的GDT，还需要告诉CPU使用新的TSS
     // 当TSS加载完毕后，CPU就可以访问到新的IST了，通过修改IDT条目告诉CPU使用新的 double fault 专属栈
+    unsafe {
+        (*addr_of_mut!(TSS)).interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+            bootstrap_double_fault_stack();
+    }
     GDT.0.load();
 
     use x86_64::instructions::segmentation::{Segment, CS};
@@ -56,3 +63,15 @@ pub fn init() {
         load_tss(GDT.1.tss_selector);
     }
 }
+
+/// 把 double fault 的 IST 栈换成从内核栈区域分配的、下面带 guard page 的栈。
+///
+/// 需要在 `memory::init_kernel_memory` 之后调用。CPU 在异常发生时才从 TSS 中读取
+/// IST，所以不需要重新加载 TSS。
+pub fn init_guarded_stacks() -> Result<StackBounds, StackError> {
+    let stack = stack::alloc_stack(DOUBLE_FAULT_STACK_PAGES)?;
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        (*addr_of_mut!(TSS)).interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = stack.top;
+    });
+    Ok(stack)
+}
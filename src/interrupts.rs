@@ -1,19 +1,19 @@
 use crate::print;
 use crate::println;
 
-use crate::gdt;
+use crate::memory;
 use lazy_static::lazy_static;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
+pub mod apic;
+mod exceptions;
+pub mod irq;
+pub use exceptions::{crash_report, DescriptorTable, Registers, SelectorErrorCode};
+
 use pic8259::ChainedPics;
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
-
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
 //                      ____________                          ____________
 // Real Time Clock --> |            |   Timer -------------> |            |
 // ACPI -------------> |            |   Keyboard-----------> |            |      _____
@@ -23,9 +23,78 @@ pub static PICS: spin::Mutex<ChainedPics> =
 // Co-Processor -----> |            |   Parallel Port 2/3 -> |            |
 // Primary ATA ------> |            |   Floppy disk -------> |            |
 // Secondary ATA ----> |____________|   Parallel Port 1----> |____________|
-pub enum InterruptIndex {
-    Timer = PIC_1_OFFSET,
-    Keyboard,
+pub static PICS: spin::Mutex<ChainedPics> =
+    spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+/// 中断处理结束: 使用 APIC 时通知 Local APIC，否则通知 8259。
+pub fn end_of_interrupt(vector: u8) {
+    if apic::is_enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(vector) };
+    }
+}
+
+/// 在 8259 中屏蔽或者打开 `irq`。打开从片上的线时同时打开主片上的级联线 IRQ 2。
+fn set_pic_masked(irq: u8, masked: bool) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut pics = PICS.lock();
+        let [mut primary, mut secondary] = unsafe { pics.read_masks() };
+        let (mask, line) = if irq < 8 {
+            (&mut primary, irq)
+        } else {
+            (&mut secondary, irq - 8)
+        };
+        if masked {
+            *mask |= 1 << line;
+        } else {
+            *mask &= !(1 << line);
+            if irq >= 8 {
+                primary &= !(1 << 2);
+            }
+        }
+        unsafe { pics.write_masks(primary, secondary) };
+    });
+}
+
+/// 8259 上的 IRQ 7 和 IRQ 15 可能是伪中断，这时 ISR 中对应的位没有置位，不能发送 EOI。
+/// 从片的伪中断要给主片发送 EOI，因为主片认为级联线上确实来了中断。
+fn pic_spurious(irq: u8) -> bool {
+    use x86_64::instructions::port::Port;
+
+    if apic::is_enabled() || (irq != 7 && irq != 15) {
+        return false;
+    }
+    // OCW3: 下一次读命令端口返回 ISR
+    let mut command = Port::<u8>::new(if irq == 7 { 0x20 } else { 0xa0 });
+    let isr = unsafe {
+        command.write(0x0b);
+        command.read()
+    };
+    if isr & 0x80 != 0 {
+        return false;
+    }
+    if irq == 15 {
+        unsafe { PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + 2) };
+    }
+    true
+}
+
+/// 重新映射 8259 并屏蔽所有的线，之后 `irq::register` 只打开注册了处理函数的线。
+/// 然后注册内核自带设备 (键盘和 COM1) 的处理函数。
+pub fn init_pics() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut pics = PICS.lock();
+        unsafe {
+            pics.initialize();
+            // initialize 恢复了 BIOS 留下的屏蔽字
+            pics.write_masks(0xff, 0xff);
+        }
+    });
+
+    // 内核自带设备的处理函数一直保留，不需要句柄
+    let _ = irq::register(irq::KEYBOARD, &keyboard_interrupt).expect("IRQ line full");
+    let _ = irq::register(irq::COM1, &serial_interrupt).expect("IRQ line full");
 }
 
 lazy_static! {
@@ -33,13 +102,9 @@ lazy_static! {
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler);
         idt.page_fault.set_handler_fn(page_fault_handler);
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        }
-        idt[InterruptIndex::Timer as usize].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard as usize].set_handler_fn(keyboard_interrupt_handler);
+        exceptions::set_handlers(&mut idt);
+        irq::set_handlers(&mut idt);
+        idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
         idt
     };
 }
@@ -48,7 +113,6 @@ extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
-use crate::hlt_loop;
 use x86_64::structures::idt::PageFaultErrorCode;
 extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
@@ -56,30 +120,57 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
-}
-
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    // _error_code always zero
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-}
+    let registers = Registers::capture();
+    let addr = Cr2::read();
+    // 写入写时复制的页: 复制帧 (或者直接恢复可写) 之后重新执行该指令
+    if error_code
+        .contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE)
+        && memory::cow::handle_write_fault(addr)
+    {
+        return;
+    }
+    if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        // 地址空间创建之后内核新建的 4 级表项: 从内核页表复制过来
+        if memory::address_space::handle_kernel_slot_fault(addr) {
+            return;
+        }
+        // 访问的是预留但还没有映射的页: 分配一个帧映射上去，返回后重新执行该指令
+        if memory::vma::handle_page_fault(addr) {
+            return;
+        }
+    }
 
-extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
-    print!(".");
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer as u8);
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        // 页存在但访问被禁止: 执行不可执行的页 (NX) 或者写只读页
+        let cause = if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "instruction fetch from non-executable page"
+        } else if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "write to read-only page"
+        } else {
+            "access to protected page"
+        };
+        crash_report(
+            "PAGE FAULT (PROTECTION VIOLATION)",
+            &stack_frame,
+            Some(format_args!(
+                "Cause: {}\nAccessed Address: {:?}\nError Code: {:?}",
+                cause, addr, error_code
+            )),
+            &registers,
+        );
     }
+    crash_report(
+        "PAGE FAULT (#PF)",
+        &stack_frame,
+        Some(format_args!(
+            "Accessed Address: {:?}\nError Code: {:?}",
+            addr, error_code
+        )),
+        &registers,
+    );
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: InterruptStackFrame) {
+fn keyboard_interrupt() -> bool {
     use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
     use spin::Mutex;
     use x86_64::instructions::port::Port;
@@ -102,13 +193,23 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: InterruptStack
             }
         }
     }
+    true
+}
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard as u8);
+fn serial_interrupt() -> bool {
+    // 收到的字节回显到屏幕上，读完数据中断也就清除了。没有数据时中断不是
+    // 接收引起的 (或者来自共享这条线的其它设备)
+    let mut received = false;
+    while let Some(byte) = crate::serial::try_receive() {
+        print!("{}", byte as char);
+        received = true;
     }
+    received
 }
 
+// Local APIC 的伪中断不需要 EOI
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
 pub fn init_idt() {
     IDT.load();
 
@@ -0,0 +1,203 @@
+use core::{
+    arch::x86_64::__cpuid,
+    ptr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+use spin::Mutex;
+use x86_64::{instructions::port::Port, PhysAddr, VirtAddr};
+
+use super::irq::{self, IRQ_LINES};
+use crate::{acpi, memory};
+
+/// Vector of the Local APIC's spurious interrupts.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+// Local APIC 寄存器的偏移
+const LAPIC_ID: u64 = 0x20;
+const LAPIC_TPR: u64 = 0x80;
+const LAPIC_EOI: u64 = 0xb0;
+const LAPIC_SVR: u64 = 0xf0;
+const LAPIC_SVR_ENABLE: u32 = 1 << 8;
+
+// I/O APIC 寄存器
+const IOAPIC_REGSEL: u64 = 0x00;
+const IOAPIC_WIN: u64 = 0x10;
+const IOAPIC_VER: u32 = 0x01;
+const IOAPIC_REDTBL: u32 = 0x10;
+const REDTBL_ACTIVE_LOW: u32 = 1 << 13;
+const REDTBL_LEVEL: u32 = 1 << 15;
+const REDTBL_MASKED: u32 = 1 << 16;
+
+// 映射后的 Local APIC 寄存器的虚拟地址，0 表示还在使用 8259
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+static IO_APIC: AtomicU64 = AtomicU64::new(0);
+
+/// 一个 ISA 中断在 I/O APIC 中的重定向项。
+#[derive(Debug, Clone, Copy)]
+struct Route {
+    gsi: u32,
+    low: u32,
+    high: u32,
+}
+
+static ROUTES: Mutex<[Option<Route>; IRQ_LINES]> = Mutex::new([None; IRQ_LINES]);
+
+/// 判断 CPU 是否有 Local APIC (CPUID.01h:EDX[9])。
+pub fn local_apic_supported() -> bool {
+    // 较新的编译器里 __cpuid 不再是 unsafe 的
+    #[allow(unused_unsafe)]
+    let result = unsafe { __cpuid(1) };
+    result.edx & (1 << 9) != 0
+}
+
+/// 中断是否经过 APIC 而不是 8259 传递。
+pub fn is_enabled() -> bool {
+    LOCAL_APIC.load(Ordering::Acquire) != 0
+}
+
+/// 通知 Local APIC 中断处理结束。
+pub fn end_of_interrupt() {
+    let base = LOCAL_APIC.load(Ordering::Acquire);
+    unsafe { write_lapic(base, LAPIC_EOI, 0) };
+}
+
+unsafe fn write_lapic(base: u64, offset: u64, value: u32) {
+    ptr::write_volatile((base + offset) as *mut u32, value);
+}
+
+unsafe fn read_lapic(base: u64, offset: u64) -> u32 {
+    ptr::read_volatile((base + offset) as *const u32)
+}
+
+unsafe fn write_ioapic(base: u64, register: u32, value: u32) {
+    ptr::write_volatile((base + IOAPIC_REGSEL) as *mut u32, register);
+    ptr::write_volatile((base + IOAPIC_WIN) as *mut u32, value);
+}
+
+unsafe fn read_ioapic(base: u64, register: u32) -> u32 {
+    ptr::write_volatile((base + IOAPIC_REGSEL) as *mut u32, register);
+    ptr::read_volatile((base + IOAPIC_WIN) as *const u32)
+}
+
+/// 屏蔽两个 8259 的所有中断。它们已经被重新映射到 32-47，之后来的伪中断不会
+/// 被当成异常。
+fn disable_pics() {
+    unsafe {
+        Port::<u8>::new(0x21).write(0xff);
+        Port::<u8>::new(0xa1).write(0xff);
+    }
+}
+
+/// 检测到 Local APIC 并且 ACPI MADT 中有 I/O APIC 时，屏蔽 8259，启用 Local APIC，
+/// 并通过 I/O APIC 把 ISA 中断 (定时器、键盘、串口等) 发给当前 CPU。
+///
+/// 返回是否切换到了 APIC，否则继续使用 8259。需要先初始化内核内存和堆
+/// (寄存器通过 `memory::mmio` 映射)。
+pub fn init() -> bool {
+    if !local_apic_supported() {
+        return false;
+    }
+    let physical_memory_offset =
+        memory::physical_memory_offset().expect("kernel memory not initialized");
+    let madt = match unsafe { acpi::madt(physical_memory_offset) } {
+        Some(madt) => madt,
+        None => return false,
+    };
+    let io_apic = match madt.io_apics.iter().find(|io_apic| io_apic.gsi_base == 0) {
+        Some(io_apic) => *io_apic,
+        None => return false,
+    };
+
+    let lapic = match map(madt.local_apic_address) {
+        Some(lapic) => lapic,
+        None => return false,
+    };
+    let ioapic = match map(io_apic.address) {
+        Some(ioapic) => ioapic,
+        None => {
+            let _ = memory::mmio::unmap_mmio(VirtAddr::new(lapic));
+            return false;
+        }
+    };
+
+    // 只打开已经有处理函数的线，其它的在注册时再打开
+    let mut registered = [false; IRQ_LINES];
+    for (irq, registered) in registered.iter_mut().enumerate() {
+        *registered = irq::has_handlers(irq as u8);
+    }
+
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        disable_pics();
+
+        // 接收所有优先级的中断，打开 APIC 并设置伪中断向量
+        write_lapic(lapic, LAPIC_TPR, 0);
+        let svr = read_lapic(lapic, LAPIC_SVR);
+        write_lapic(
+            lapic,
+            LAPIC_SVR,
+            svr | LAPIC_SVR_ENABLE | SPURIOUS_VECTOR as u32,
+        );
+        let lapic_id = (read_lapic(lapic, LAPIC_ID) >> 24) as u8;
+
+        // 先屏蔽所有的重定向项，再打开需要的
+        let entries = ((read_ioapic(ioapic, IOAPIC_VER) >> 16) & 0xff) + 1;
+        for gsi in 0..entries {
+            write_ioapic(ioapic, IOAPIC_REDTBL + 2 * gsi, REDTBL_MASKED);
+        }
+        // IRQ 2 是 8259 的级联线，不会真的发生
+        let mut routes = ROUTES.lock();
+        for irq in (0..IRQ_LINES as u8).filter(|&irq| irq != 2) {
+            let (gsi, over) = madt.isa_irq(irq);
+            if gsi >= entries {
+                continue;
+            }
+            let mut low = irq::vector(irq) as u32;
+            if over.map_or(false, |o| o.active_low()) {
+                low |= REDTBL_ACTIVE_LOW;
+            }
+            if over.map_or(false, |o| o.level_triggered()) {
+                low |= REDTBL_LEVEL;
+            }
+            let route = Route {
+                gsi,
+                low,
+                high: (lapic_id as u32) << 24,
+            };
+            write_route(ioapic, &route, !registered[irq as usize]);
+            routes[irq as usize] = Some(route);
+        }
+
+        IO_APIC.store(ioapic, Ordering::Release);
+        LOCAL_APIC.store(lapic, Ordering::Release);
+    });
+    true
+}
+
+unsafe fn write_route(ioapic: u64, route: &Route, masked: bool) {
+    let low = if masked {
+        route.low | REDTBL_MASKED
+    } else {
+        route.low
+    };
+    write_ioapic(ioapic, IOAPIC_REDTBL + 2 * route.gsi + 1, route.high);
+    write_ioapic(ioapic, IOAPIC_REDTBL + 2 * route.gsi, low);
+}
+
+/// 在 I/O APIC 中屏蔽或者打开 ISA 中断 `irq`，还在使用 8259 时什么也不做。
+pub fn set_irq_masked(irq: u8, masked: bool) {
+    let ioapic = IO_APIC.load(Ordering::Acquire);
+    if ioapic == 0 {
+        return;
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if let Some(route) = ROUTES.lock()[irq as usize] {
+            unsafe { write_route(ioapic, &route, masked) };
+        }
+    });
+}
+
+fn map(phys: PhysAddr) -> Option<u64> {
+    memory::mmio::map_mmio(phys, 4096)
+        .ok()
+        .map(|virt| virt.as_u64())
+}
@@ -0,0 +1,315 @@
+use core::{arch::asm, fmt, mem::MaybeUninit};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+use crate::{serial_print, vga_buffer::WRITER};
+
+// 同时输出到串口和屏幕，崩溃时屏幕可能看不到，测试时只有串口。
+// 先写串口；异常可能发生在持有屏幕锁的代码里，这时跳过屏幕而不是死锁
+macro_rules! report {
+    ($($arg:tt)*) => {{
+        serial_print!("{}\n", format_args!($($arg)*));
+        if let Some(mut writer) = WRITER.try_lock() {
+            use core::fmt::Write;
+            let _ = writeln!(writer, "{}", format_args!($($arg)*));
+        }
+    }};
+}
+
+/// 异常处理函数入口处的通用寄存器。
+///
+/// 在 `x86-interrupt` 函数开头读取，大部分是被中断代码的值，但编译器在这之前
+/// 使用过的寄存器 (比如保存指针的那个) 可能已经改变。
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+}
+
+impl Registers {
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let mut registers = MaybeUninit::<Registers>::uninit();
+        unsafe {
+            asm!(
+                "mov [{0} + 0x00], rax",
+                "mov [{0} + 0x08], rbx",
+                "mov [{0} + 0x10], rcx",
+                "mov [{0} + 0x18], rdx",
+                "mov [{0} + 0x20], rsi",
+                "mov [{0} + 0x28], rdi",
+                "mov [{0} + 0x30], rbp",
+                "mov [{0} + 0x38], r8",
+                "mov [{0} + 0x40], r9",
+                "mov [{0} + 0x48], r10",
+                "mov [{0} + 0x50], r11",
+                "mov [{0} + 0x58], r12",
+                "mov [{0} + 0x60], r13",
+                "mov [{0} + 0x68], r14",
+                "mov [{0} + 0x70], r15",
+                in(reg) registers.as_mut_ptr(),
+                options(nostack, preserves_flags),
+            );
+            registers.assume_init()
+        }
+    }
+}
+
+/// 段选择子错误码所指的描述符表。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+/// #TS、#NP、#SS 和 #GP 的错误码，指出出错的段选择子。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectorErrorCode(pub u64);
+
+impl SelectorErrorCode {
+    /// The exception was caused by an event external to the program.
+    pub fn external(&self) -> bool {
+        self.0 & 1 != 0
+    }
+
+    pub fn table(&self) -> DescriptorTable {
+        match (self.0 >> 1) & 0b11 {
+            0b00 => DescriptorTable::Gdt,
+            0b10 => DescriptorTable::Ldt,
+            _ => DescriptorTable::Idt,
+        }
+    }
+
+    pub fn index(&self) -> u64 {
+        (self.0 >> 3) & 0x1fff
+    }
+}
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == 0 {
+            // #GP 的错误码为 0 表示不是由段选择子引起的
+            return write!(f, "{:#x} (not selector related)", self.0);
+        }
+        write!(
+            f,
+            "{:#x} (selector index {} in {:?}{})",
+            self.0,
+            self.index(),
+            self.table(),
+            if self.external() { ", external" } else { "" }
+        )
+    }
+}
+
+/// 打印统一格式的崩溃报告，然后 panic，集成测试会因此以失败退出 QEMU。
+pub fn crash_report(
+    title: &str,
+    stack_frame: &InterruptStackFrame,
+    details: Option<fmt::Arguments>,
+    registers: &Registers,
+) -> ! {
+    report!("EXCEPTION: {}", title);
+    if let Some(details) = details {
+        report!("{}", details);
+    }
+    print_state(stack_frame, registers);
+    panic!("EXCEPTION: {}", title);
+}
+
+fn print_state(stack_frame: &InterruptStackFrame, r: &Registers) {
+    report!(
+        "RIP={:#018x} CS={:#06x} RFLAGS={:#018x}",
+        stack_frame.instruction_pointer.as_u64(),
+        stack_frame.code_segment,
+        stack_frame.cpu_flags
+    );
+    report!(
+        "RSP={:#018x} SS={:#06x}",
+        stack_frame.stack_pointer.as_u64(),
+        stack_frame.stack_segment
+    );
+    report!(
+        "RAX={:#018x} RBX={:#018x} RCX={:#018x} RDX={:#018x}",
+        r.rax,
+        r.rbx,
+        r.rcx,
+        r.rdx
+    );
+    report!(
+        "RSI={:#018x} RDI={:#018x} RBP={:#018x}",
+        r.rsi,
+        r.rdi,
+        r.rbp
+    );
+    report!(
+        "R8 ={:#018x} R9 ={:#018x} R10={:#018x} R11={:#018x}",
+        r.r8,
+        r.r9,
+        r.r10,
+        r.r11
+    );
+    report!(
+        "R12={:#018x} R13={:#018x} R14={:#018x} R15={:#018x}",
+        r.r12,
+        r.r13,
+        r.r14,
+        r.r15
+    );
+}
+
+// 生成打印崩溃报告的处理函数，按错误码的种类分三种
+macro_rules! fatal_handler {
+    ($name:ident, $title:expr) => {
+        extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame) {
+            let registers = Registers::capture();
+            crash_report($title, &stack_frame, None, &registers);
+        }
+    };
+    ($name:ident, $title:expr, selector) => {
+        extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame, error_code: u64) {
+            let registers = Registers::capture();
+            let error_code = SelectorErrorCode(error_code);
+            crash_report(
+                $title,
+                &stack_frame,
+                Some(format_args!("Error Code: {}", error_code)),
+                &registers,
+            );
+        }
+    };
+    ($name:ident, $title:expr, error_code) => {
+        extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame, error_code: u64) {
+            let registers = Registers::capture();
+            crash_report(
+                $title,
+                &stack_frame,
+                Some(format_args!("Error Code: {:#x}", error_code)),
+                &registers,
+            );
+        }
+    };
+}
+
+fatal_handler!(divide_error_handler, "DIVIDE ERROR (#DE)");
+fatal_handler!(overflow_handler, "OVERFLOW (#OF)");
+fatal_handler!(bound_range_handler, "BOUND RANGE EXCEEDED (#BR)");
+fatal_handler!(invalid_opcode_handler, "INVALID OPCODE (#UD)");
+fatal_handler!(device_not_available_handler, "DEVICE NOT AVAILABLE (#NM)");
+fatal_handler!(x87_floating_point_handler, "X87 FLOATING POINT (#MF)");
+fatal_handler!(simd_floating_point_handler, "SIMD FLOATING POINT (#XM)");
+fatal_handler!(virtualization_handler, "VIRTUALIZATION (#VE)");
+fatal_handler!(invalid_tss_handler, "INVALID TSS (#TS)", selector);
+fatal_handler!(
+    segment_not_present_handler,
+    "SEGMENT NOT PRESENT (#NP)",
+    selector
+);
+fatal_handler!(stack_segment_handler, "STACK SEGMENT FAULT (#SS)", selector);
+fatal_handler!(
+    general_protection_handler,
+    "GENERAL PROTECTION FAULT (#GP)",
+    selector
+);
+fatal_handler!(alignment_check_handler, "ALIGNMENT CHECK (#AC)", error_code);
+fatal_handler!(
+    vmm_communication_handler,
+    "VMM COMMUNICATION (#VC)",
+    error_code
+);
+fatal_handler!(
+    security_exception_handler,
+    "SECURITY EXCEPTION (#SX)",
+    error_code
+);
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    let registers = Registers::capture();
+    crash_report("MACHINE CHECK (#MC)", &stack_frame, None, &registers);
+}
+
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: InterruptStackFrame,
+    _error_code: u64,
+) -> ! {
+    // _error_code always zero
+    let registers = Registers::capture();
+    crash_report("DOUBLE FAULT (#DF)", &stack_frame, None, &registers);
+}
+
+// 调试异常和 NMI 不是错误，打印之后继续执行
+extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
+    let registers = Registers::capture();
+    report!("EXCEPTION: DEBUG (#DB)");
+    print_state(&stack_frame, &registers);
+}
+
+extern "x86-interrupt" fn non_maskable_interrupt_handler(stack_frame: InterruptStackFrame) {
+    let registers = Registers::capture();
+    report!("NON-MASKABLE INTERRUPT");
+    print_state(&stack_frame, &registers);
+}
+
+/// 设置除了断点和缺页以外的所有 CPU 异常的处理函数。
+pub(super) fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    idt.divide_error.set_handler_fn(divide_error_handler);
+    idt.debug.set_handler_fn(debug_handler);
+    idt.non_maskable_interrupt
+        .set_handler_fn(non_maskable_interrupt_handler);
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded.set_handler_fn(bound_range_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.device_not_available
+        .set_handler_fn(device_not_available_handler);
+    unsafe {
+        idt.double_fault
+            .set_handler_fn(double_fault_handler)
+            .set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX);
+    }
+    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+    idt.segment_not_present
+        .set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault
+        .set_handler_fn(stack_segment_handler);
+    idt.general_protection_fault
+        .set_handler_fn(general_protection_handler);
+    idt.x87_floating_point
+        .set_handler_fn(x87_floating_point_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    idt.machine_check.set_handler_fn(machine_check_handler);
+    idt.simd_floating_point
+        .set_handler_fn(simd_floating_point_handler);
+    idt.virtualization.set_handler_fn(virtualization_handler);
+    idt.vmm_communication_exception
+        .set_handler_fn(vmm_communication_handler);
+    idt.security_exception
+        .set_handler_fn(security_exception_handler);
+}
+
+#[test_case]
+fn test_selector_error_code() {
+    // index 2 in the IDT, caused by an external event
+    let code = SelectorErrorCode(2 << 3 | 0b011);
+    assert!(code.external());
+    assert_eq!(code.table(), DescriptorTable::Idt);
+    assert_eq!(code.index(), 2);
+
+    let code = SelectorErrorCode(5 << 3 | 0b100);
+    assert!(!code.external());
+    assert_eq!(code.table(), DescriptorTable::Ldt);
+    assert_eq!(code.index(), 5);
+}
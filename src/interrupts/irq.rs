@@ -0,0 +1,157 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts::without_interrupts,
+    structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
+};
+
+use super::{apic, end_of_interrupt, pic_spurious, set_pic_masked, PIC_1_OFFSET};
+
+/// Number of legacy IRQ lines, IRQ `n` is delivered on vector `PIC_1_OFFSET + n`.
+pub const IRQ_LINES: usize = 16;
+/// How many handlers can share one IRQ line.
+pub const MAX_SHARED_HANDLERS: usize = 4;
+
+// 常用的 ISA 中断号
+pub const TIMER: u8 = 0;
+pub const KEYBOARD: u8 = 1;
+pub const COM1: u8 = 4;
+pub const RTC: u8 = 8;
+
+/// 中断处理函数，返回 true 表示中断确实是它的设备发出的。
+///
+/// 普通函数 (`&my_handler`) 或者 `Box::leak` 出来的闭包都可以。处理函数在中断
+/// 上下文中运行，不能注册或者注销处理函数，也不能等待被中断代码持有的锁。
+pub type IrqHandler = &'static (dyn Fn() -> bool + Send + Sync);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    InvalidIrq,
+    /// All `MAX_SHARED_HANDLERS` slots of the line are taken.
+    Full,
+}
+
+/// `register` 返回的句柄，用来注销处理函数。
+#[derive(Debug, PartialEq, Eq)]
+#[must_use = "dropping the handle makes the handler impossible to unregister"]
+pub struct IrqHandle {
+    irq: u8,
+    slot: usize,
+}
+
+impl IrqHandle {
+    pub fn irq(&self) -> u8 {
+        self.irq
+    }
+}
+
+static HANDLERS: Mutex<[[Option<IrqHandler>; MAX_SHARED_HANDLERS]; IRQ_LINES]> =
+    Mutex::new([[None; MAX_SHARED_HANDLERS]; IRQ_LINES]);
+
+const ZERO: AtomicU64 = AtomicU64::new(0);
+static COUNTS: [AtomicU64; IRQ_LINES] = [ZERO; IRQ_LINES];
+static UNHANDLED: [AtomicU64; IRQ_LINES] = [ZERO; IRQ_LINES];
+
+/// IRQ 对应的中断向量。
+pub const fn vector(irq: u8) -> u8 {
+    PIC_1_OFFSET + irq
+}
+
+/// 给 `irq` 添加一个处理函数，同一条线上的处理函数按注册顺序全部调用。
+///
+/// 第一个处理函数注册后才打开这条线 (I/O APIC 或者 8259)。
+pub fn register(irq: u8, handler: IrqHandler) -> Result<IrqHandle, IrqError> {
+    if irq as usize >= IRQ_LINES {
+        return Err(IrqError::InvalidIrq);
+    }
+    let (slot, first) = without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let line = &mut handlers[irq as usize];
+        let slot = line
+            .iter()
+            .position(|handler| handler.is_none())
+            .ok_or(IrqError::Full)?;
+        line[slot] = Some(handler);
+        Ok((slot, line.iter().flatten().count() == 1))
+    })?;
+    if first {
+        set_masked(irq, false);
+    }
+    Ok(IrqHandle { irq, slot })
+}
+
+/// 注销处理函数。最后一个处理函数被注销后，屏蔽这条线。
+pub fn unregister(handle: IrqHandle) {
+    let last = without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let line = &mut handlers[handle.irq as usize];
+        line[handle.slot] = None;
+        line.iter().all(|handler| handler.is_none())
+    });
+    if last {
+        set_masked(handle.irq, true);
+    }
+}
+
+fn set_masked(irq: u8, masked: bool) {
+    if apic::is_enabled() {
+        apic::set_irq_masked(irq, masked);
+    } else {
+        set_pic_masked(irq, masked);
+    }
+}
+
+/// `irq` 上是否注册了处理函数。
+pub fn has_handlers(irq: u8) -> bool {
+    without_interrupts(|| HANDLERS.lock()[irq as usize].iter().any(Option::is_some))
+}
+
+/// `irq` 总共发生过多少次。
+pub fn count(irq: u8) -> u64 {
+    COUNTS[irq as usize].load(Ordering::Relaxed)
+}
+
+/// `irq` 有多少次没有任何处理函数认领。
+pub fn unhandled(irq: u8) -> u64 {
+    UNHANDLED[irq as usize].load(Ordering::Relaxed)
+}
+
+fn dispatch(irq: u8) {
+    if pic_spurious(irq) {
+        return;
+    }
+    COUNTS[irq as usize].fetch_add(1, Ordering::Relaxed);
+    // 复制一份，调用处理函数时不持有锁
+    let handlers = HANDLERS.lock()[irq as usize];
+    let mut handled = false;
+    for handler in handlers.iter().flatten() {
+        handled |= handler();
+    }
+    if !handled {
+        UNHANDLED[irq as usize].fetch_add(1, Ordering::Relaxed);
+    }
+    end_of_interrupt(vector(irq));
+}
+
+// x86-interrupt 函数不知道自己的向量，每条线生成一个入口
+macro_rules! irq_stubs {
+    ($($irq:literal => $name:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                dispatch($irq);
+            }
+        )*
+
+        /// 把所有 IRQ 的向量指向分发函数。
+        pub(super) fn set_handlers(idt: &mut InterruptDescriptorTable) {
+            $(idt[vector($irq) as usize].set_handler_fn($name);)*
+        }
+    };
+}
+
+irq_stubs! {
+    0 => irq0, 1 => irq1, 2 => irq2, 3 => irq3,
+    4 => irq4, 5 => irq5, 6 => irq6, 7 => irq7,
+    8 => irq8, 9 => irq9, 10 => irq10, 11 => irq11,
+    12 => irq12, 13 => irq13, 14 => irq14, 15 => irq15,
+}
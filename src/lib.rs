@@ -7,12 +7,14 @@
 #![feature(alloc_error_handler)]
 #![feature(const_mut_refs)]
 
+pub mod acpi;
 pub mod allocator;
 pub mod gdt;
 pub mod interrupts;
 pub mod memory;
 pub mod serial;
 pub mod task;
+pub mod time;
 pub mod vga_buffer;
 use core::{alloc::Layout, panic::PanicInfo};
 
@@ -52,8 +54,8 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
 pub fn init() {
     gdt::init();
     interrupts::init_idt();
-    // PIC init
-    unsafe { interrupts::PICS.lock().initialize() };
+    interrupts::init_pics();
+    time::init();
     x86_64::instructions::interrupts::enable();
 }
 
@@ -18,7 +18,7 @@ use alloc::vec::Vec;
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use rust_os::allocator;
-use rust_os::memory::BootInfoFrameAllocator;
+use rust_os::memory::BitmapFrameAllocator;
 use rust_os::task::simple_executor::SimpleExecutor;
 use rust_os::task::Task;
 use x86_64::VirtAddr;
@@ -59,9 +59,28 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     rust_os::init();
 
     let phy_mom_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { rust_os::memory::init(phy_mom_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    rust_os::memory::report::print_boot_report(&boot_info.memory_map, phy_mom_offset);
+    let mapper = unsafe { rust_os::memory::init(phy_mom_offset) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phy_mom_offset) };
+    rust_os::memory::init_kernel_memory(mapper, frame_allocator);
+    let wx = rust_os::memory::protection::enforce_wx();
+    println!(
+        "W^X: {} code pages made read-only, {} data pages made non-executable",
+        wx.text_pages, wx.data_pages
+    );
+    allocator::init_heap().expect("heap initialization failed");
+    if rust_os::interrupts::apic::init() {
+        println!("interrupts: using local APIC and I/O APIC");
+    } else {
+        println!("interrupts: no APIC found, using 8259 PIC");
+    }
+    println!("{}", rust_os::time::tsc::calibrate());
+    println!("RTC: {} UTC", rust_os::time::rtc::read());
+    rust_os::gdt::init_guarded_stacks().expect("kernel stack allocation failed");
+    // 初始化完成，bootloader 的内存可以回收了，之后不能再访问 boot_info
+    let reclaimed = unsafe { rust_os::memory::reclaim::reclaim_boot_memory(&boot_info.memory_map) };
+    println!("{}", reclaimed);
 
     let mut executor = SimpleExecutor::new();
     executor.spawn(Task::new(example_task()));
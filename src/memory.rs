@@ -1,9 +1,26 @@
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::slice;
 use x86_64::{
     registers::control::Cr3,
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    structures::paging::{
+        mapper::{MapToError, MappedFrame, TranslateResult},
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+        PageTableFlags, PhysFrame, Size2MiB, Size4KiB, Translate,
+    },
     PhysAddr, VirtAddr,
 };
+
+pub mod address_space;
+pub mod buddy;
+pub mod cow;
+pub mod mmio;
+pub mod paging;
+pub mod protection;
+pub mod reclaim;
+pub mod report;
+pub mod stack;
+pub mod vma;
+
 /// 返回一个对活动的4级表的可变引用。
 unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
     let (level_4_table_frame, _) = Cr3::read();
@@ -14,11 +31,212 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
     &mut *page_table_ptr
 }
 
+/// 初始化一个新的OffsetPageTable，并开启 NX 位以便之后的映射可以使用 `NO_EXECUTE`，
+/// 以及写保护，让只读的映射对内核也生效。
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    protection::enable_nxe();
+    protection::enable_write_protect();
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+/// 内核的页表和帧分配器。
+pub struct KernelMemory {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: BitmapFrameAllocator,
+}
+
+impl KernelMemory {
+    /// 在 `addr` 处映射一个新分配的页，返回映射的字节数。
+    ///
+    /// `addr` 按 2 MiB 对齐并且还需要至少 `remaining >= 2 MiB` 时先尝试用一个 2 MiB 大页，
+    /// 没有连续的物理帧或者该区域已经有 4 KiB 的映射时退回 4 KiB 页。
+    pub fn map_anonymous_page(
+        &mut self,
+        addr: VirtAddr,
+        remaining: u64,
+        flags: PageTableFlags,
+    ) -> Result<u64, MapToError<Size4KiB>> {
+        if addr.is_aligned(Size2MiB::SIZE) && remaining >= Size2MiB::SIZE {
+            let frame: Option<PhysFrame<Size2MiB>> = self.frame_allocator.allocate_frame();
+            if let Some(frame) = frame {
+                let page = Page::containing_address(addr);
+                match unsafe {
+                    self.mapper
+                        .map_to(page, frame, flags, &mut self.frame_allocator)
+                } {
+                    Ok(flush) => {
+                        flush.flush();
+                        return Ok(Size2MiB::SIZE);
+                    }
+                    Err(_) => unsafe { self.frame_allocator.deallocate_frame(frame) },
+                }
+            }
+        }
+
+        let frame = self
+            .frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let page = Page::<Size4KiB>::containing_address(addr);
+        match unsafe {
+            self.mapper
+                .map_to(page, frame, flags, &mut self.frame_allocator)
+        } {
+            Ok(flush) => {
+                flush.flush();
+                Ok(Size4KiB::SIZE)
+            }
+            Err(err) => {
+                unsafe { self.frame_allocator.deallocate_frame(frame) };
+                Err(err)
+            }
+        }
+    }
+
+    /// 把 `virt` 映射到物理地址 `phys`，返回映射的字节数。
+    ///
+    /// 两个地址都按 2 MiB 对齐并且 `remaining >= 2 MiB` 时用一个 2 MiB 大页，否则用 4 KiB 页。
+    ///
+    /// 这个函数是不安全的，因为调用者必须保证 `phys` 处的内存可以被这样访问。
+    pub unsafe fn map_physical_page(
+        &mut self,
+        virt: VirtAddr,
+        phys: PhysAddr,
+        remaining: u64,
+        flags: PageTableFlags,
+    ) -> Result<u64, MapToError<Size4KiB>> {
+        if virt.is_aligned(Size2MiB::SIZE)
+            && phys.is_aligned(Size2MiB::SIZE)
+            && remaining >= Size2MiB::SIZE
+        {
+            let page = Page::<Size2MiB>::containing_address(virt);
+            let frame = PhysFrame::<Size2MiB>::containing_address(phys);
+            if let Ok(flush) = self
+                .mapper
+                .map_to(page, frame, flags, &mut self.frame_allocator)
+            {
+                flush.flush();
+                return Ok(Size2MiB::SIZE);
+            }
+        }
+
+        let page = Page::<Size4KiB>::containing_address(virt);
+        let frame = PhysFrame::<Size4KiB>::containing_address(phys);
+        self.mapper
+            .map_to(page, frame, flags, &mut self.frame_allocator)?
+            .flush();
+        Ok(Size4KiB::SIZE)
+    }
+
+    /// 取消包含 `addr` 的页 (4 KiB 或 2 MiB) 的映射，返回它映射到的物理地址和页大小。
+    ///
+    /// 帧不会被释放，需要时调用者用 `free_frames` 交还给帧分配器。
+    pub fn unmap_page(&mut self, addr: VirtAddr) -> Option<(PhysAddr, u64)> {
+        match self.mapper.translate(addr) {
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size2MiB(_),
+                ..
+            } => {
+                let page = Page::<Size2MiB>::containing_address(addr);
+                let (frame, flush) = self.mapper.unmap(page).ok()?;
+                flush.flush();
+                Some((frame.start_address(), Size2MiB::SIZE))
+            }
+            TranslateResult::Mapped {
+                frame: MappedFrame::Size4KiB(_),
+                ..
+            } => {
+                let page = Page::<Size4KiB>::containing_address(addr);
+                let (frame, flush) = self.mapper.unmap(page).ok()?;
+                flush.flush();
+                Some((frame.start_address(), Size4KiB::SIZE))
+            }
+            _ => None,
+        }
+    }
+
+    /// 内核 4 级页表所在的帧。
+    pub fn level_4_frame(&mut self) -> PhysFrame {
+        let offset = self.mapper.phys_offset();
+        let table = self.mapper.level_4_table() as *mut PageTable as u64;
+        PhysFrame::containing_address(PhysAddr::new(table - offset.as_u64()))
+    }
+
+    /// 用当前 `Cr3` 指向的 4 级页表调用 `f`。切换到别的地址空间时，缺页处理
+    /// 要修改的是那个地址空间的页表，而不是内核的。
+    pub fn with_active_mapper<R>(
+        &mut self,
+        f: impl FnOnce(&mut OffsetPageTable<'static>, &mut BitmapFrameAllocator) -> R,
+    ) -> R {
+        let (active, _) = Cr3::read();
+        if active == self.level_4_frame() {
+            return f(&mut self.mapper, &mut self.frame_allocator);
+        }
+        let offset = self.mapper.phys_offset();
+        let mut mapper = unsafe { OffsetPageTable::new(active_level_4_table(offset), offset) };
+        f(&mut mapper, &mut self.frame_allocator)
+    }
+
+    /// 把 `unmap_page` 返回的帧交还给帧分配器。
+    ///
+    /// 这个函数是不安全的，因为调用者必须保证这些帧不再被使用。
+    pub unsafe fn free_frames(&mut self, phys: PhysAddr, page_size: u64) {
+        if page_size == Size2MiB::SIZE {
+            let frame = PhysFrame::<Size2MiB>::containing_address(phys);
+            self.frame_allocator.deallocate_frame(frame);
+        } else {
+            let frame = PhysFrame::<Size4KiB>::containing_address(phys);
+            self.frame_allocator.deallocate_frame(frame);
+        }
+    }
+}
+
+static KERNEL_MEMORY: spin::Mutex<Option<KernelMemory>> = spin::Mutex::new(None);
+
+/// 交出内核页表和帧分配器，之后堆扩展、缺页处理等需要在运行时映射页面的地方
+/// 通过 `with_kernel_memory` 共用它们。
+///
+/// 同时预先分配内核栈和 MMIO 窗口的 4 级表项，让它们在每个 `AddressSpace` 中共享。
+pub fn init_kernel_memory(
+    mut mapper: OffsetPageTable<'static>,
+    mut frame_allocator: BitmapFrameAllocator,
+) {
+    for (start, size) in [
+        (stack::KERNEL_STACKS_START, stack::KERNEL_STACKS_SIZE),
+        (mmio::MMIO_START, mmio::MMIO_SIZE),
+    ] {
+        address_space::preallocate_kernel_slots(&mut mapper, &mut frame_allocator, start, size);
+    }
+    *KERNEL_MEMORY.lock() = Some(KernelMemory {
+        mapper,
+        frame_allocator,
+    });
+}
+
+/// 持有锁访问内核页表和帧分配器，还没有调用 `init_kernel_memory` 时返回 `None`。
+///
+/// 堆扩展时也需要这个锁，所以 `f` 里面不能在堆上分配内存。
+pub fn with_kernel_memory<R>(f: impl FnOnce(&mut KernelMemory) -> R) -> Option<R> {
+    KERNEL_MEMORY.lock().as_mut().map(f)
+}
+
+/// 缺页处理中使用的 `with_kernel_memory`。
+///
+/// 缺页发生在持有这个锁的代码里时，等待锁只会死锁，所以直接 panic。
+pub(crate) fn with_kernel_memory_in_fault<R>(f: impl FnOnce(&mut KernelMemory) -> R) -> Option<R> {
+    KERNEL_MEMORY
+        .try_lock()
+        .expect("page fault while the kernel memory lock is held")
+        .as_mut()
+        .map(f)
+}
+
+/// bootloader 映射完整物理内存的虚拟地址偏移，还没有调用 `init_kernel_memory` 时返回 `None`。
+pub fn physical_memory_offset() -> Option<VirtAddr> {
+    with_kernel_memory(|memory| memory.mapper.phys_offset())
+}
+
 /// 从bootloader的MemoryMap中返回可用的 frames。
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
@@ -57,3 +275,220 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
         frame
     }
 }
+
+const FRAME_SIZE: u64 = Size4KiB::SIZE;
+
+/// 一个帧最多可以被多少个页共享，见 `BitmapFrameAllocator::add_ref`。
+pub const MAX_FRAME_REFS: usize = u16::MAX as usize + 1;
+
+/// 用位图记录每个物理帧是否空闲的帧分配器，支持释放帧。
+///
+/// 位图本身存放在第一个足够大的可用内存区域的开头，通过 bootloader 映射的
+/// 物理内存偏移访问。位为 1 表示该帧已被占用或不可用。位图后面是每个帧的共享
+/// 计数 (写时复制用)。
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    // 除了第一个页之外还有多少个页映射着这个帧
+    shares: &'static mut [u16],
+    total_frames: usize,
+    used_frames: usize,
+    // 下一次从哪个 word 开始查找空闲帧
+    next_word: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// 从传递的内存 map 中创建一个 BitmapFrameAllocator。
+    ///
+    /// 这个函数是不安全的，因为调用者必须保证传递的内存 map 是有效的，
+    /// 并且完整的物理内存被映射在 `physical_memory_offset` 处。
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+        };
+
+        // 位图需要覆盖到最高的可用帧
+        let max_addr = usable_regions()
+            .map(|r| r.range.end_addr())
+            .max()
+            .unwrap_or(0);
+        let frame_count = (max_addr / FRAME_SIZE) as usize;
+        let words = (frame_count + 63) / 64;
+        let bitmap_size = (words * 8) as u64;
+        let shares_size = (words * 64 * 2) as u64;
+
+        let bitmap_region = usable_regions()
+            .find(|r| r.range.end_addr() - r.range.start_addr() >= bitmap_size + shares_size)
+            .expect("no usable region large enough for the frame bitmap");
+        let bitmap_start = bitmap_region.range.start_addr();
+        let bitmap_ptr = (physical_memory_offset + bitmap_start).as_mut_ptr::<u64>();
+        let bitmap = slice::from_raw_parts_mut(bitmap_ptr, words);
+        let shares_ptr = (physical_memory_offset + bitmap_start + bitmap_size).as_mut_ptr::<u16>();
+        let shares = slice::from_raw_parts_mut(shares_ptr, words * 64);
+        shares.fill(0);
+
+        // 先把所有帧标记为不可用，再放开可用区域
+        bitmap.fill(u64::MAX);
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            shares,
+            total_frames: 0,
+            used_frames: 0,
+            next_word: 0,
+        };
+        for region in usable_regions() {
+            let start = region.range.start_addr() / FRAME_SIZE;
+            let end = region.range.end_addr() / FRAME_SIZE;
+            for index in start..end {
+                allocator.clear(index as usize);
+                allocator.total_frames += 1;
+            }
+        }
+
+        // 位图和共享计数占用的帧不能再分配出去
+        let bitmap_frames = (bitmap_size + shares_size + FRAME_SIZE - 1) / FRAME_SIZE;
+        for index in 0..bitmap_frames {
+            allocator.set((bitmap_start / FRAME_SIZE + index) as usize);
+            allocator.total_frames -= 1;
+        }
+
+        allocator
+    }
+
+    /// 可以分配的帧的总数。
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    /// 已分配出去的帧数。
+    pub fn used_frames(&self) -> usize {
+        self.used_frames
+    }
+
+    /// 仍然空闲的帧数。
+    pub fn free_frames(&self) -> usize {
+        self.total_frames - self.used_frames
+    }
+
+    /// 把 `start..end` 中原来不可用的帧交给分配器，返回加入的帧数。
+    ///
+    /// 超出位图范围的帧会被忽略。这个函数是不安全的，因为调用者必须保证这些帧
+    /// 确实不再被使用。
+    pub unsafe fn add_frames(&mut self, start: PhysAddr, end: PhysAddr) -> usize {
+        let start = (start.align_up(FRAME_SIZE).as_u64() / FRAME_SIZE) as usize;
+        let end = ((end.as_u64() / FRAME_SIZE) as usize).min(self.bitmap.len() * 64);
+        let mut added = 0;
+        for index in start..end {
+            // 已经空闲的帧本来就属于分配器
+            if self.is_set(index) {
+                self.clear(index);
+                added += 1;
+            }
+        }
+        self.total_frames += added;
+        added
+    }
+
+    /// 映射着 `frame` 的页数，没有被共享的帧是 1。
+    pub fn ref_count(&self, frame: PhysFrame) -> usize {
+        let index = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        self.shares
+            .get(index)
+            .map_or(1, |&shares| shares as usize + 1)
+    }
+
+    /// 多一个页映射 `frame`。已经有 `MAX_FRAME_REFS` 个页或者帧不归分配器管理时
+    /// 返回 false。
+    pub fn add_ref(&mut self, frame: PhysFrame) -> bool {
+        let index = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        match self.shares.get_mut(index) {
+            Some(shares) if *shares < u16::MAX => {
+                *shares += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// 少一个页映射 `frame`，返回 true 表示这是最后一个，调用者可以释放这个帧。
+    pub fn drop_ref(&mut self, frame: PhysFrame) -> bool {
+        let index = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        match self.shares.get_mut(index) {
+            Some(shares) if *shares > 0 => {
+                *shares -= 1;
+                false
+            }
+            _ => true,
+        }
+    }
+
+    fn is_set(&self, index: usize) -> bool {
+        self.bitmap[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn set(&mut self, index: usize) {
+        self.bitmap[index / 64] |= 1 << (index % 64);
+    }
+
+    fn clear(&mut self, index: usize) {
+        self.bitmap[index / 64] &= !(1 << (index % 64));
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let words = self.bitmap.len();
+        // 从上次的位置开始找一个还有空位的 word，必要时绕回开头
+        let word = (0..words)
+            .map(|i| (self.next_word + i) % words)
+            .find(|&i| self.bitmap[i] != u64::MAX)?;
+        let index = word * 64 + self.bitmap[word].trailing_ones() as usize;
+
+        self.set(index);
+        self.used_frames += 1;
+        self.next_word = word;
+        let addr = PhysAddr::new(index as u64 * FRAME_SIZE);
+        Some(PhysFrame::containing_address(addr))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let index = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        assert!(self.is_set(index), "double free of frame {:?}", frame);
+        self.clear(index);
+        self.used_frames -= 1;
+    }
+}
+
+// 一个 2 MiB 的帧在位图中正好占 8 个对齐的 word
+const HUGE_FRAME_WORDS: usize = (Size2MiB::SIZE / FRAME_SIZE / 64) as usize;
+
+unsafe impl FrameAllocator<Size2MiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let chunk = self
+            .bitmap
+            .chunks_exact(HUGE_FRAME_WORDS)
+            .position(|words| words.iter().all(|&word| word == 0))?;
+        let words = chunk * HUGE_FRAME_WORDS..(chunk + 1) * HUGE_FRAME_WORDS;
+        self.bitmap[words].fill(u64::MAX);
+        self.used_frames += HUGE_FRAME_WORDS * 64;
+        let addr = PhysAddr::new(chunk as u64 * Size2MiB::SIZE);
+        Some(PhysFrame::containing_address(addr))
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        let chunk = (frame.start_address().as_u64() / Size2MiB::SIZE) as usize;
+        let words = &mut self.bitmap[chunk * HUGE_FRAME_WORDS..(chunk + 1) * HUGE_FRAME_WORDS];
+        assert!(
+            words.iter().all(|&word| word == u64::MAX),
+            "double free of frame {:?}",
+            frame
+        );
+        words.fill(0);
+        self.used_frames -= HUGE_FRAME_WORDS * 64;
+    }
+}
@@ -0,0 +1,290 @@
+use core::ptr;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page,
+        PageSize, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{
+    cow, with_kernel_memory, with_kernel_memory_in_fault, BitmapFrameAllocator, KernelMemory,
+};
+
+/// 用户地址空间的范围。这些 4 级表项属于各个地址空间，其余的 4 级表项都属于内核，
+/// 所有地址空间共享。
+pub const USER_START: u64 = 0x_7000_0000_0000;
+pub const USER_END: u64 = 0x_8000_0000_0000;
+
+/// 第 `index` 个 4 级表项是否在用户地址空间里。
+pub fn is_user_slot(index: usize) -> bool {
+    (USER_START >> 39) as usize <= index && index < (USER_END >> 39) as usize
+}
+
+/// 给覆盖 `start..start + size` 的、还没有使用的内核 4 级表项分配一个空的 3 级表，
+/// 之后在这里新建的映射只会修改共享的下级页表，已经存在的地址空间也能看到。
+///
+/// 其它内核表项在第一次使用时才分配，已经存在的地址空间在缺页时复制它们 (见
+/// `handle_kernel_slot_fault`)。内核可能在持有内核内存锁时或者在中断里访问的区域
+/// (内核栈、MMIO 窗口、堆) 没法这样处理，要预先分配。
+pub(super) fn preallocate_kernel_slots(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BitmapFrameAllocator,
+    start: u64,
+    size: u64,
+) {
+    let first = (start >> 39) as usize;
+    let last = ((start + size - 1) >> 39) as usize;
+    for index in first..=last {
+        assert!(!is_user_slot(index), "kernel range overlaps the user range");
+        assert!(
+            allocate_kernel_slot(mapper, frame_allocator, index),
+            "no frames left for kernel page tables"
+        );
+    }
+}
+
+/// `preallocate_kernel_slots` 的公开版本，给 `memory` 之外在运行时映射内存的区域
+/// (比如堆) 使用。需要先调用 `memory::init_kernel_memory`。
+pub fn preallocate_kernel_range(start: VirtAddr, size: u64) {
+    with_kernel_memory(|memory| {
+        preallocate_kernel_slots(
+            &mut memory.mapper,
+            &mut memory.frame_allocator,
+            start.as_u64(),
+            size,
+        )
+    })
+    .expect("kernel memory not initialized");
+}
+
+fn allocate_kernel_slot(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut BitmapFrameAllocator,
+    index: usize,
+) -> bool {
+    let offset = mapper.phys_offset();
+    let entry = &mut mapper.level_4_table()[index];
+    if !entry.is_unused() {
+        return true;
+    }
+    let frame: PhysFrame = match frame_allocator.allocate_frame() {
+        Some(frame) => frame,
+        None => return false,
+    };
+    unsafe { (*table_ptr(frame.start_address(), offset)).zero() };
+    entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    true
+}
+
+/// 让当前 `Cr3` 指向的地址空间有 `addr` 所在的内核 4 级表项: `allocate` 时内核还
+/// 没有这个表项就先给内核分配一个，然后复制到活动的 4 级表。复制了表项时返回 true。
+pub(super) fn sync_kernel_slot(memory: &mut KernelMemory, addr: VirtAddr, allocate: bool) -> bool {
+    let index = usize::from(addr.p4_index());
+    if is_user_slot(index) {
+        return false;
+    }
+    if allocate && !allocate_kernel_slot(&mut memory.mapper, &mut memory.frame_allocator, index) {
+        return false;
+    }
+    let (active, _) = Cr3::read();
+    if active == memory.level_4_frame() {
+        return false;
+    }
+    let entry = memory.mapper.level_4_table()[index].clone();
+    let table = unsafe { &mut *table_ptr(active.start_address(), memory.mapper.phys_offset()) };
+    if entry.is_unused() || !table[index].is_unused() {
+        return false;
+    }
+    table[index] = entry;
+    true
+}
+
+/// 缺页处理: 地址空间创建之后内核才用到的 4 级表项从内核页表复制过来，返回 true
+/// 表示可以重新执行该指令。
+///
+/// 缺页发生在持有内核内存锁的代码里时 panic。
+pub fn handle_kernel_slot_fault(addr: VirtAddr) -> bool {
+    with_kernel_memory_in_fault(|memory| sync_kernel_slot(memory, addr, false)).unwrap_or(false)
+}
+
+#[derive(Debug)]
+pub enum AddressSpaceError {
+    /// The page lies outside `USER_START..USER_END`, in the part shared with the kernel.
+    KernelRange,
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for AddressSpaceError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        AddressSpaceError::Map(err)
+    }
+}
+
+/// 一个独立的地址空间，有自己的 4 级页表。
+///
+/// 创建时复制内核的 4 级表项，它们指向共享的下级页表，内核的映射 (包括之后新建的)
+/// 在所有地址空间中共享；`USER_START..USER_END` 属于这个地址空间，可以映射用户页。
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    physical_memory_offset: VirtAddr,
+}
+
+impl AddressSpace {
+    /// 分配一个新的 4 级页表，共享内核的映射。
+    ///
+    /// 需要先调用 `memory::init_kernel_memory`。
+    pub fn new() -> Result<Self, MapToError<Size4KiB>> {
+        with_kernel_memory(|memory| {
+            let frame = memory
+                .frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            let physical_memory_offset = memory.mapper.phys_offset();
+            let table = unsafe { &mut *table_ptr(frame.start_address(), physical_memory_offset) };
+
+            table.zero();
+            for (index, entry) in memory.mapper.level_4_table().iter().enumerate() {
+                if !is_user_slot(index) {
+                    table[index] = entry.clone();
+                }
+            }
+
+            Ok(AddressSpace {
+                level_4_frame: frame,
+                physical_memory_offset,
+            })
+        })
+        .expect("kernel memory not initialized")
+    }
+
+    /// The frame of the level 4 table, as loaded into `Cr3`.
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    pub(super) fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let table = table_ptr(
+            self.level_4_frame.start_address(),
+            self.physical_memory_offset,
+        );
+        unsafe { OffsetPageTable::new(&mut *table, self.physical_memory_offset) }
+    }
+
+    /// 在这个地址空间里给 `page` 映射一个清零的帧，用户态可以访问。
+    ///
+    /// 可写的页总是加上 `NO_EXECUTE` (W^X)。
+    pub fn map_user(
+        &mut self,
+        page: Page,
+        flags: PageTableFlags,
+    ) -> Result<PhysFrame, AddressSpaceError> {
+        if !is_user_slot(usize::from(page.p4_index())) {
+            return Err(AddressSpaceError::KernelRange);
+        }
+        let mut flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if flags.contains(PageTableFlags::WRITABLE) {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+
+        let mut mapper = self.mapper();
+        with_kernel_memory(|memory| {
+            let frame = memory
+                .frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            let frame_ptr = memory.mapper.phys_offset() + frame.start_address().as_u64();
+            unsafe { ptr::write_bytes(frame_ptr.as_mut_ptr::<u8>(), 0, Size4KiB::SIZE as usize) };
+
+            match unsafe { mapper.map_to(page, frame, flags, &mut memory.frame_allocator) } {
+                // 这个地址空间不一定是活动的，刷新 TLB 也没有坏处
+                Ok(flush) => {
+                    flush.flush();
+                    Ok(frame)
+                }
+                Err(err) => {
+                    unsafe { memory.frame_allocator.deallocate_frame(frame) };
+                    Err(err.into())
+                }
+            }
+        })
+        .expect("kernel memory not initialized")
+    }
+
+    /// 在这个地址空间里翻译虚拟地址。
+    pub fn translate(&mut self, addr: VirtAddr) -> Option<PhysAddr> {
+        self.mapper().translate_addr(addr)
+    }
+
+    /// 写 `Cr3` 切换到这个地址空间。
+    ///
+    /// 这个函数是不安全的，因为调用者必须保证在这个地址空间被释放之前切换回去。
+    pub unsafe fn switch_to(&self) {
+        let (_, flags) = Cr3::read();
+        Cr3::write(self.level_4_frame, flags);
+    }
+
+    fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+}
+
+/// 切换回内核的页表。
+pub fn switch_to_kernel() {
+    let frame =
+        with_kernel_memory(|memory| memory.level_4_frame()).expect("kernel memory not initialized");
+    let (_, flags) = Cr3::read();
+    unsafe { Cr3::write(frame, flags) };
+}
+
+fn table_ptr(phys: PhysAddr, physical_memory_offset: VirtAddr) -> *mut PageTable {
+    (physical_memory_offset + phys.as_u64()).as_mut_ptr()
+}
+
+impl Drop for AddressSpace {
+    /// 释放这个地址空间自己的页表和映射的帧，共享的内核部分不动。
+    fn drop(&mut self) {
+        if self.is_active() {
+            switch_to_kernel();
+        }
+        let offset = self.physical_memory_offset;
+        let level_4_table = unsafe { &mut *table_ptr(self.level_4_frame.start_address(), offset) };
+
+        with_kernel_memory(|memory| {
+            for (index, entry) in level_4_table.iter_mut().enumerate() {
+                if is_user_slot(index) && !entry.is_unused() {
+                    unsafe { free_table(memory, entry.addr(), 3, offset) };
+                    entry.set_unused();
+                }
+            }
+            unsafe { memory.frame_allocator.deallocate_frame(self.level_4_frame) };
+        });
+    }
+}
+
+/// 释放 `table` (第 `level` 级) 以及它下面所有的页表和映射的帧。
+unsafe fn free_table(memory: &mut KernelMemory, table: PhysAddr, level: u8, offset: VirtAddr) {
+    for entry in (*table_ptr(table, offset))
+        .iter()
+        .filter(|entry| !entry.is_unused())
+    {
+        if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            // 写时复制共享的帧别处还在使用时不释放
+            if entry.flags().contains(cow::COW)
+                && !memory
+                    .frame_allocator
+                    .drop_ref(PhysFrame::containing_address(entry.addr()))
+            {
+                continue;
+            }
+            let page_size = Size4KiB::SIZE << (9 * (level as u64 - 1));
+            memory.free_frames(entry.addr(), page_size);
+        } else {
+            free_table(memory, entry.addr(), level - 1, offset);
+        }
+    }
+    memory
+        .frame_allocator
+        .deallocate_frame(PhysFrame::<Size4KiB>::containing_address(table));
+}
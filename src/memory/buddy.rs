@@ -0,0 +1,243 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::{
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+/// Order of a block of 512 frames, i.e. a 2 MiB huge page.
+pub const ORDER_2MIB: usize = 9;
+/// Order of a block of 512 * 512 frames, i.e. a 1 GiB huge page.
+pub const ORDER_1GIB: usize = 18;
+/// The largest block the allocator manages.
+pub const MAX_ORDER: usize = ORDER_1GIB;
+
+// 空闲链表的结尾
+const NONE: u64 = u64::MAX;
+
+/// 伙伴系统物理帧分配器，分配 2^order 个物理上连续、按块大小对齐的帧。
+///
+/// 空闲块组成的链表直接存放在空闲帧里 (每个空闲块的开头保存下一个块的物理
+/// 地址)，通过 bootloader 映射的物理内存偏移访问。释放时如果伙伴块也空闲，
+/// 就合并成更高一阶的块。
+pub struct BuddyFrameAllocator {
+    free_lists: [u64; MAX_ORDER + 1],
+    physical_memory_offset: VirtAddr,
+    total_frames: usize,
+    free_frames: usize,
+}
+
+impl BuddyFrameAllocator {
+    /// Creates an allocator that manages no memory yet, see `add_region`.
+    pub const fn new(physical_memory_offset: VirtAddr) -> Self {
+        BuddyFrameAllocator {
+            free_lists: [NONE; MAX_ORDER + 1],
+            physical_memory_offset,
+            total_frames: 0,
+            free_frames: 0,
+        }
+    }
+
+    /// 用内存 map 中所有可用的区域创建一个 BuddyFrameAllocator。
+    ///
+    /// 这个函数是不安全的，因为调用者必须保证内存 map 是有效的，完整的物理内存
+    /// 被映射在 `physical_memory_offset` 处，并且这些区域没有交给其它帧分配器。
+    pub unsafe fn init(memory_map: &'static MemoryMap, physical_memory_offset: VirtAddr) -> Self {
+        let mut allocator = Self::new(physical_memory_offset);
+        for region in memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+        {
+            allocator.add_region(
+                PhysAddr::new(region.range.start_addr()),
+                PhysAddr::new(region.range.end_addr()),
+            );
+        }
+        allocator
+    }
+
+    /// Hands the physical memory in `start..end` to the allocator.
+    ///
+    /// The range is split into the largest naturally aligned blocks that fit.
+    /// Unsafe because the caller must guarantee that the memory is unused.
+    pub unsafe fn add_region(&mut self, start: PhysAddr, end: PhysAddr) {
+        let mut addr = start.align_up(Size4KiB::SIZE).as_u64();
+        let end = end.align_down(Size4KiB::SIZE).as_u64();
+        while addr < end {
+            let order = (0..=MAX_ORDER)
+                .rev()
+                .find(|&order| addr % block_size(order) == 0 && addr + block_size(order) <= end)
+                .unwrap();
+            self.push(addr, order);
+            self.total_frames += 1 << order;
+            self.free_frames += 1 << order;
+            addr += block_size(order);
+        }
+    }
+
+    /// Allocates `2^order` contiguous frames aligned to the block size.
+    pub fn allocate(&mut self, order: usize) -> Option<PhysFrame> {
+        assert!(order <= MAX_ORDER, "order {} too large", order);
+        // 找到能满足要求的最小的块
+        let mut current = (order..=MAX_ORDER).find(|&o| self.free_lists[o] != NONE)?;
+        let addr = self.pop(current).unwrap();
+
+        // 把多余的部分拆成伙伴放回空闲链表
+        while current > order {
+            current -= 1;
+            unsafe { self.push(addr + block_size(current), current) };
+        }
+
+        self.free_frames -= 1 << order;
+        Some(PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+
+    /// Allocates the smallest block that holds `count` contiguous frames.
+    ///
+    /// Returns the first frame and the order needed to free the block.
+    pub fn allocate_frames(&mut self, count: usize) -> Option<(PhysFrame, usize)> {
+        let order = order_for(count)?;
+        self.allocate(order).map(|frame| (frame, order))
+    }
+
+    /// Frees a block returned by `allocate` with the same order, merging it
+    /// with its buddy as long as the buddy is free as well.
+    ///
+    /// Unsafe because the caller must guarantee that the block is unused.
+    pub unsafe fn deallocate(&mut self, frame: PhysFrame, order: usize) {
+        let mut addr = frame.start_address().as_u64();
+        assert_eq!(addr % block_size(order), 0, "misaligned block");
+        self.free_frames += 1 << order;
+
+        let mut order = order;
+        while order < MAX_ORDER {
+            let buddy = addr ^ block_size(order);
+            if !self.remove(buddy, order) {
+                break;
+            }
+            addr = addr.min(buddy);
+            order += 1;
+        }
+        self.push(addr, order);
+    }
+
+    /// 管理的帧的总数。
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    /// 仍然空闲的帧数。
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Number of free blocks of the given order.
+    pub fn free_blocks(&self, order: usize) -> usize {
+        let mut count = 0;
+        let mut current = self.free_lists[order];
+        while current != NONE {
+            count += 1;
+            current = unsafe { *self.next_ptr(current) };
+        }
+        count
+    }
+
+    fn next_ptr(&self, addr: u64) -> *mut u64 {
+        (self.physical_memory_offset + addr).as_mut_ptr()
+    }
+
+    unsafe fn push(&mut self, addr: u64, order: usize) {
+        *self.next_ptr(addr) = self.free_lists[order];
+        self.free_lists[order] = addr;
+    }
+
+    fn pop(&mut self, order: usize) -> Option<u64> {
+        let addr = self.free_lists[order];
+        if addr == NONE {
+            return None;
+        }
+        self.free_lists[order] = unsafe { *self.next_ptr(addr) };
+        Some(addr)
+    }
+
+    /// Removes the block at `addr` from the free list of `order`.
+    ///
+    /// Returns false if the block is not free.
+    fn remove(&mut self, addr: u64, order: usize) -> bool {
+        let mut prev: Option<u64> = None;
+        let mut current = self.free_lists[order];
+        while current != NONE {
+            let next = unsafe { *self.next_ptr(current) };
+            if current == addr {
+                match prev {
+                    Some(prev) => unsafe { *self.next_ptr(prev) = next },
+                    None => self.free_lists[order] = next,
+                }
+                return true;
+            }
+            prev = Some(current);
+            current = next;
+        }
+        false
+    }
+}
+
+/// Size in bytes of a block of the given order.
+pub const fn block_size(order: usize) -> u64 {
+    Size4KiB::SIZE << order
+}
+
+/// Smallest order whose blocks hold `count` frames.
+pub fn order_for(count: usize) -> Option<usize> {
+    let order = count.max(1).next_power_of_two().trailing_zeros() as usize;
+    if order <= MAX_ORDER {
+        Some(order)
+    } else {
+        None
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        self.allocate(0)
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        let frame = self.allocate(ORDER_2MIB)?;
+        Some(PhysFrame::containing_address(frame.start_address()))
+    }
+}
+
+unsafe impl FrameAllocator<Size1GiB> for BuddyFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size1GiB>> {
+        let frame = self.allocate(ORDER_1GIB)?;
+        Some(PhysFrame::containing_address(frame.start_address()))
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.deallocate(frame, 0)
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        self.deallocate(
+            PhysFrame::containing_address(frame.start_address()),
+            ORDER_2MIB,
+        )
+    }
+}
+
+impl FrameDeallocator<Size1GiB> for BuddyFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size1GiB>) {
+        self.deallocate(
+            PhysFrame::containing_address(frame.start_address()),
+            ORDER_1GIB,
+        )
+    }
+}
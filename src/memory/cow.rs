@@ -0,0 +1,204 @@
+use core::ptr;
+use x86_64::{
+    instructions::tlb,
+    structures::paging::{
+        mapper::{MapToError, MappedFrame, TranslateResult},
+        FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags,
+        PhysFrame, Size4KiB, Translate,
+    },
+    VirtAddr,
+};
+
+use super::{
+    address_space::{self, AddressSpace},
+    with_kernel_memory, with_kernel_memory_in_fault, BitmapFrameAllocator,
+};
+
+/// 软件定义的写时复制标志，只和 `WRITABLE` 清除的页一起出现。
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+
+#[derive(Debug)]
+pub enum CowError {
+    /// The source page is not mapped with a 4 KiB page.
+    NotMapped,
+    /// The frame is shared by `MAX_FRAME_REFS` pages already.
+    TooManyShared,
+    /// The destination page lies outside `USER_START..USER_END`.
+    KernelRange,
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for CowError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        CowError::Map(err)
+    }
+}
+
+/// 返回共享 `frame` 的页数。
+pub fn ref_count(frame: PhysFrame) -> usize {
+    with_kernel_memory(|memory| memory.frame_allocator.ref_count(frame))
+        .expect("kernel memory not initialized")
+}
+
+/// 把 `dst` 映射到 `src` 所映射的帧上，以写时复制的方式共享。两个页都在当前
+/// `Cr3` 指向的页表中。
+///
+/// 可写的页在两边都变成只读并加上 `COW` 标志，第一次写入时由缺页处理复制。
+pub fn share_page(src: Page, dst: Page) -> Result<(), CowError> {
+    with_kernel_memory(|memory| {
+        memory.with_active_mapper(|mapper, frame_allocator| {
+            let (frame, flags) = mark_shared(mapper, src, frame_allocator)?;
+            map_shared(mapper, dst, frame, flags, frame_allocator)
+        })
+    })
+    .expect("kernel memory not initialized")
+}
+
+/// 和 `share_page` 一样，但是 `dst` 映射在 `space` 中，用来复制地址空间。
+/// `src` 在当前 `Cr3` 指向的页表中。
+pub fn share_page_into(src: Page, space: &mut AddressSpace, dst: Page) -> Result<(), CowError> {
+    if !address_space::is_user_slot(usize::from(dst.p4_index())) {
+        return Err(CowError::KernelRange);
+    }
+    let mut dst_mapper = space.mapper();
+    with_kernel_memory(|memory| {
+        memory.with_active_mapper(|mapper, frame_allocator| {
+            let (frame, flags) = mark_shared(mapper, src, frame_allocator)?;
+            map_shared(&mut dst_mapper, dst, frame, flags, frame_allocator)
+        })
+    })
+    .expect("kernel memory not initialized")
+}
+
+/// 把 `src` 改成只读的 `COW` 页 (原来是可写的话)，返回它的帧和共享时用的 flags。
+///
+/// 之后映射 `dst` 失败时 `src` 保持 `COW`，第一次写入时发现没有别的页共享，
+/// 直接恢复可写。
+fn mark_shared(
+    mapper: &mut OffsetPageTable,
+    src: Page,
+    frame_allocator: &BitmapFrameAllocator,
+) -> Result<(PhysFrame, PageTableFlags), CowError> {
+    let (frame, flags) = match mapper.translate(src.start_address()) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } => (frame, flags),
+        _ => return Err(CowError::NotMapped),
+    };
+    if frame_allocator.ref_count(frame) >= super::MAX_FRAME_REFS {
+        return Err(CowError::TooManyShared);
+    }
+    let flags = flags - PageTableFlags::ACCESSED - PageTableFlags::DIRTY;
+    let shared_flags = if flags.intersects(PageTableFlags::WRITABLE | COW) {
+        (flags - PageTableFlags::WRITABLE) | COW
+    } else {
+        flags
+    };
+
+    if shared_flags != flags {
+        unsafe { mapper.update_flags(src, shared_flags) }
+            .map_err(|_| CowError::NotMapped)?
+            .flush();
+    }
+    Ok((frame, shared_flags))
+}
+
+fn map_shared(
+    mapper: &mut OffsetPageTable,
+    dst: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+    frame_allocator: &mut BitmapFrameAllocator,
+) -> Result<(), CowError> {
+    // 页本身是只读的，上级页表仍然要可写，复制之后才能写入
+    let table_flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | (flags & PageTableFlags::USER_ACCESSIBLE);
+    if !frame_allocator.add_ref(frame) {
+        return Err(CowError::TooManyShared);
+    }
+    let mapped =
+        unsafe { mapper.map_to_with_table_flags(dst, frame, flags, table_flags, frame_allocator) };
+    match mapped {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(err) => {
+            frame_allocator.drop_ref(frame);
+            Err(err.into())
+        }
+    }
+}
+
+/// 缺页处理: 写入一个 `COW` 页时，帧还被共享就复制一份，否则直接让页重新可写。
+///
+/// 修改的是当前 `Cr3` 指向的页表。不使用堆；缺页发生在持有内核内存锁的代码里时
+/// panic。处理了这个缺页时返回 true。
+pub fn handle_write_fault(addr: VirtAddr) -> bool {
+    let page = Page::<Size4KiB>::containing_address(addr);
+
+    with_kernel_memory_in_fault(|memory| {
+        memory.with_active_mapper(|mapper, frame_allocator| {
+            let (frame, flags) = match mapper.translate(page.start_address()) {
+                TranslateResult::Mapped {
+                    frame: MappedFrame::Size4KiB(frame),
+                    flags,
+                    ..
+                } => (frame, flags),
+                _ => return false,
+            };
+            if !flags.contains(COW) {
+                return false;
+            }
+            let writable_flags = (flags - COW - PageTableFlags::ACCESSED - PageTableFlags::DIRTY)
+                | PageTableFlags::WRITABLE;
+
+            if frame_allocator.ref_count(frame) == 1 {
+                // 最后一个使用者，不需要复制
+                return match unsafe { mapper.update_flags(page, writable_flags) } {
+                    Ok(flush) => {
+                        flush.flush();
+                        true
+                    }
+                    Err(_) => false,
+                };
+            }
+
+            // 分配不到帧时页保持原样，缺页处理报告这个缺页
+            let copy: PhysFrame = match frame_allocator.allocate_frame() {
+                Some(copy) => copy,
+                None => return false,
+            };
+            let phys_offset = mapper.phys_offset();
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    (phys_offset + frame.start_address().as_u64()).as_ptr::<u8>(),
+                    (phys_offset + copy.start_address().as_u64()).as_mut_ptr::<u8>(),
+                    Size4KiB::SIZE as usize,
+                );
+                remap(mapper, page, copy, writable_flags);
+            }
+            // 这个页不再引用原来的帧，其它页还在用它
+            frame_allocator.drop_ref(frame);
+            true
+        })
+    })
+    .unwrap_or(false)
+}
+
+/// 把已经映射的 4 KiB 页 `page` 的页表项直接改成指向 `frame`。
+///
+/// 中间不会有页没有映射的时刻，也不需要分配页表，所以不会失败。
+unsafe fn remap(mapper: &mut OffsetPageTable, page: Page, frame: PhysFrame, flags: PageTableFlags) {
+    let offset = mapper.phys_offset();
+    let mut table: &mut PageTable = mapper.level_4_table();
+    for index in [page.p4_index(), page.p3_index(), page.p2_index()] {
+        let next = offset + table[index].addr().as_u64();
+        table = &mut *next.as_mut_ptr::<PageTable>();
+    }
+    table[page.p1_index()].set_addr(frame.start_address(), flags);
+    tlb::flush(page.start_address());
+}
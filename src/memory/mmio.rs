@@ -0,0 +1,143 @@
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::{
+    align_up,
+    structures::paging::{
+        mapper::MapToError, PageSize, PageTableFlags, PhysFrame, Size2MiB, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use super::{with_kernel_memory, KernelMemory};
+
+/// 设备内存映射专用的虚拟地址区域。
+pub const MMIO_START: u64 = 0x_6666_0000_0000;
+pub const MMIO_SIZE: u64 = 64 * 1024 * 1024 * 1024; // 64 GiB
+
+/// Flags of MMIO pages: uncached, never executable.
+pub const MMIO_FLAGS: PageTableFlags = PageTableFlags::PRESENT
+    .union(PageTableFlags::WRITABLE)
+    .union(PageTableFlags::NO_CACHE)
+    .union(PageTableFlags::WRITE_THROUGH)
+    .union(PageTableFlags::NO_EXECUTE);
+
+/// MMIO 窗口中一段已映射的区域 (按页对齐)。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct MmioRegion {
+    start: u64,
+    size: u64,
+}
+
+// 按地址排序
+static REGIONS: Mutex<Vec<MmioRegion>> = Mutex::new(Vec::new());
+
+#[derive(Debug)]
+pub enum MmioError {
+    Empty,
+    /// No free range of the requested size is left in the window.
+    WindowFull,
+    /// `phys + len` lies outside the physical address space.
+    OutOfRange,
+    /// The address was not returned by `map_mmio`.
+    NotMapped,
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for MmioError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        MmioError::Map(err)
+    }
+}
+
+/// 把物理地址 `phys..phys + len` 的设备内存映射到 MMIO 窗口里，不经过缓存。
+///
+/// `phys` 不需要页对齐，返回的虚拟地址和 `phys` 在页内的偏移相同。
+/// 2 MiB 以上的映射在对齐的部分使用 2 MiB 大页。
+/// 需要先调用 `memory::init_kernel_memory` 并初始化堆。
+pub fn map_mmio(phys: PhysAddr, len: u64) -> Result<VirtAddr, MmioError> {
+    if len == 0 {
+        return Err(MmioError::Empty);
+    }
+    match phys.as_u64().checked_add(len) {
+        Some(end) if PhysAddr::try_new(end - 1).is_ok() => {}
+        _ => return Err(MmioError::OutOfRange),
+    }
+    if len > MMIO_SIZE {
+        return Err(MmioError::WindowFull);
+    }
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+    let offset = phys - first_frame.start_address();
+    let size = align_up(offset + len, Size4KiB::SIZE);
+
+    // 大的映射让虚拟地址和物理地址模 2 MiB 相同，中间的部分就可以用 2 MiB 大页
+    let phase = first_frame.start_address().as_u64() % Size2MiB::SIZE;
+    let place = |addr: u64| {
+        if size < Size2MiB::SIZE {
+            addr
+        } else {
+            align_up(addr - phase, Size2MiB::SIZE) + phase
+        }
+    };
+
+    let mut regions = REGIONS.lock();
+    // 第一个放得下的空隙
+    let mut start = place(MMIO_START);
+    let mut index = 0;
+    for region in regions.iter() {
+        if start + size <= region.start {
+            break;
+        }
+        start = place(region.start + region.size);
+        index += 1;
+    }
+    if start + size > MMIO_START + MMIO_SIZE {
+        return Err(MmioError::WindowFull);
+    }
+
+    with_kernel_memory(|memory| -> Result<(), MapToError<Size4KiB>> {
+        let mut mapped = 0;
+        while mapped < size {
+            let virt = VirtAddr::new(start + mapped);
+            let phys = first_frame.start_address() + mapped;
+            match unsafe { memory.map_physical_page(virt, phys, size - mapped, MMIO_FLAGS) } {
+                Ok(page_size) => mapped += page_size,
+                Err(err) => {
+                    // 撤销已经映射的页
+                    unmap_range(memory, start, mapped);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(())
+    })
+    .expect("kernel memory not initialized")?;
+
+    regions.insert(index, MmioRegion { start, size });
+    Ok(VirtAddr::new(start + offset))
+}
+
+/// 取消 `map_mmio` 建立的映射，`virt` 是 `map_mmio` 返回的地址。
+///
+/// 设备内存的帧不属于帧分配器，所以不会被释放。
+pub fn unmap_mmio(virt: VirtAddr) -> Result<(), MmioError> {
+    let start = virt.align_down(Size4KiB::SIZE).as_u64();
+    let mut regions = REGIONS.lock();
+    let index = regions
+        .iter()
+        .position(|region| region.start == start)
+        .ok_or(MmioError::NotMapped)?;
+    let region = regions.remove(index);
+
+    with_kernel_memory(|memory| unmap_range(memory, region.start, region.size));
+    Ok(())
+}
+
+fn unmap_range(memory: &mut KernelMemory, start: u64, size: u64) {
+    let mut addr = start;
+    while addr < start + size {
+        match memory.unmap_page(VirtAddr::new(addr)) {
+            Some((_, page_size)) => addr += page_size,
+            None => addr += Size4KiB::SIZE,
+        }
+    }
+}
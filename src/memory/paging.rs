@@ -0,0 +1,230 @@
+use core::fmt;
+
+use x86_64::{
+    instructions::tlb,
+    structures::paging::{PageTable, PageTableFlags},
+    PhysAddr, VirtAddr,
+};
+
+use super::active_level_4_table;
+use crate::serial_println;
+
+/// 一个虚拟地址经过页表翻译之后的结果。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Translation {
+    pub phys: PhysAddr,
+    /// Flags of the page table entry that maps the page.
+    pub flags: PageTableFlags,
+    /// Size of the mapped page: 4 KiB, 2 MiB or 1 GiB.
+    pub page_size: u64,
+}
+
+/// 活动页表中的一个页映射。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub virt: VirtAddr,
+    pub phys: PhysAddr,
+    pub page_size: u64,
+    pub flags: PageTableFlags,
+}
+
+/// 连续的虚拟地址映射到连续的物理地址且 flags 相同的若干个页。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub virt: VirtAddr,
+    pub phys: PhysAddr,
+    pub size: u64,
+    pub flags: PageTableFlags,
+}
+
+impl Region {
+    /// Appends the mapping if it directly continues this region.
+    fn extend(&mut self, mapping: &Mapping) -> bool {
+        // 用 u64 比较，区域可能正好结束在低半部分的末尾 (非规范地址)
+        let continues = self.virt.as_u64() + self.size == mapping.virt.as_u64()
+            && self.phys.as_u64() + self.size == mapping.phys.as_u64()
+            && self.flags == mapping.flags;
+        if continues {
+            self.size += mapping.page_size;
+        }
+        continues
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#018x}-{:#018x} -> {:#014x} {:>10} KiB {:?}",
+            self.virt.as_u64(),
+            self.virt.as_u64() + self.size,
+            self.phys.as_u64(),
+            self.size / 1024,
+            self.flags
+        )
+    }
+}
+
+// 只随访问产生变化的 flags，比较映射时忽略
+const VOLATILE_FLAGS: PageTableFlags = PageTableFlags::ACCESSED.union(PageTableFlags::DIRTY);
+
+unsafe fn table_at(phys: PhysAddr, physical_memory_offset: VirtAddr) -> &'static PageTable {
+    &*(physical_memory_offset + phys.as_u64()).as_ptr()
+}
+
+/// Size of the page mapped by an entry of the given level (1 = level 1 table).
+const fn page_size(level: u8) -> u64 {
+    4096 << (9 * (level as u64 - 1))
+}
+
+/// 在活动页表中把虚拟地址翻译成物理地址，同时给出映射的 flags 和页大小。
+///
+/// 这个函数是不安全的，因为调用者必须保证完整的物理内存被映射在
+/// `physical_memory_offset` 处。
+pub unsafe fn translate(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Option<Translation> {
+    let mut table: &PageTable = active_level_4_table(physical_memory_offset);
+    let indexes = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+
+    for (i, &index) in indexes.iter().enumerate() {
+        let level = 4 - i as u8;
+        let entry = &table[index];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        // level 3 和 level 2 的表项可以直接映射 1 GiB 和 2 MiB 的大页
+        if level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            let page_size = page_size(level);
+            return Some(Translation {
+                phys: entry.addr() + (addr.as_u64() & (page_size - 1)),
+                flags: entry.flags(),
+                page_size,
+            });
+        }
+        table = table_at(entry.addr(), physical_memory_offset);
+    }
+    unreachable!()
+}
+
+/// 按虚拟地址顺序对活动页表中的每一个已映射的页调用 `f`。
+///
+/// 这个函数是不安全的，因为调用者必须保证完整的物理内存被映射在
+/// `physical_memory_offset` 处。
+pub unsafe fn for_each_mapping(physical_memory_offset: VirtAddr, mut f: impl FnMut(Mapping)) {
+    let level_4_table = active_level_4_table(physical_memory_offset);
+    walk(level_4_table, 4, 0, physical_memory_offset, &mut f);
+}
+
+unsafe fn walk(
+    table: &PageTable,
+    level: u8,
+    base: u64,
+    physical_memory_offset: VirtAddr,
+    f: &mut impl FnMut(Mapping),
+) {
+    for (index, entry) in table.iter().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let virt = base | (index as u64) << (12 + 9 * (level as u64 - 1));
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            f(Mapping {
+                virt: VirtAddr::new_truncate(virt),
+                phys: entry.addr(),
+                page_size: page_size(level),
+                flags,
+            });
+        } else {
+            let next = table_at(entry.addr(), physical_memory_offset);
+            walk(next, level - 1, virt, physical_memory_offset, f);
+        }
+    }
+}
+
+/// 遍历活动页表中的每一个映射，`f` 返回 `Some(flags)` 时把该映射的 flags 改成它
+/// 并刷新对应的 TLB 项。
+///
+/// 这个函数是不安全的：除了 `for_each_mapping` 的要求之外，修改 flags 可能让正在
+/// 使用的内存变得不可访问，并且调用者必须保证没有其它代码同时在修改页表。
+pub unsafe fn update_mappings(
+    physical_memory_offset: VirtAddr,
+    mut f: impl FnMut(&Mapping) -> Option<PageTableFlags>,
+) {
+    let level_4_table = active_level_4_table(physical_memory_offset);
+    walk_mut(level_4_table, 4, 0, physical_memory_offset, &mut f);
+}
+
+unsafe fn table_at_mut(phys: PhysAddr, physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+    &mut *(physical_memory_offset + phys.as_u64()).as_mut_ptr()
+}
+
+unsafe fn walk_mut(
+    table: &mut PageTable,
+    level: u8,
+    base: u64,
+    physical_memory_offset: VirtAddr,
+    f: &mut impl FnMut(&Mapping) -> Option<PageTableFlags>,
+) {
+    for (index, entry) in table.iter_mut().enumerate() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let virt = base | (index as u64) << (12 + 9 * (level as u64 - 1));
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            let mapping = Mapping {
+                virt: VirtAddr::new_truncate(virt),
+                phys: entry.addr(),
+                page_size: page_size(level),
+                flags,
+            };
+            if let Some(new_flags) = f(&mapping) {
+                entry.set_flags(new_flags);
+                tlb::flush(mapping.virt);
+            }
+        } else {
+            let next = table_at_mut(entry.addr(), physical_memory_offset);
+            walk_mut(next, level - 1, virt, physical_memory_offset, f);
+        }
+    }
+}
+
+/// 把活动页表中的映射合并成连续的区域，对每个区域调用 `f`。
+///
+/// 这个函数是不安全的，原因同 `for_each_mapping`。
+pub unsafe fn for_each_region(physical_memory_offset: VirtAddr, mut f: impl FnMut(Region)) {
+    let mut current: Option<Region> = None;
+    for_each_mapping(physical_memory_offset, |mut mapping| {
+        mapping.flags -= VOLATILE_FLAGS;
+        if let Some(region) = current.as_mut() {
+            if region.extend(&mapping) {
+                return;
+            }
+            f(*region);
+        }
+        current = Some(Region {
+            virt: mapping.virt,
+            phys: mapping.phys,
+            size: mapping.page_size,
+            flags: mapping.flags,
+        });
+    });
+    if let Some(region) = current {
+        f(region);
+    }
+}
+
+/// 通过串口打印活动页表的映射区域概览，用于调试。
+///
+/// 这个函数是不安全的，原因同 `for_each_mapping`。
+pub unsafe fn dump_regions(physical_memory_offset: VirtAddr) {
+    serial_println!("virtual range                            physical            size flags");
+    for_each_region(physical_memory_offset, |region| {
+        serial_println!("{}", region);
+    });
+}
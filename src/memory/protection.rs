@@ -0,0 +1,135 @@
+use core::{mem, slice};
+use x86_64::{
+    align_down, align_up,
+    registers::{
+        control::{Cr0, Cr0Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{PageSize, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+use super::{paging, with_kernel_memory};
+
+/// 开启 `EFER.NXE`，之后页表项中的 `NO_EXECUTE` 才有效 (否则是保留位)。
+pub fn enable_nxe() {
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+}
+
+/// 开启 `CR0.WP`，内核写只读的页时也会触发缺页异常 (写时复制依赖这一点)。
+pub fn enable_write_protect() {
+    unsafe { Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT)) };
+}
+
+/// `enforce_wx` 修改过的页数。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WxReport {
+    /// Pages of the kernel code that were made read-only.
+    pub text_pages: usize,
+    /// Writable pages that were made non-executable.
+    pub data_pages: usize,
+}
+
+/// 让所有映射满足 W^X: 内核的代码段重新映射为只读，其它所有可写的页都加上
+/// `NO_EXECUTE`，包括 bootloader 建立的映射。
+///
+/// 代码段是内核 ELF 程序头中可执行的 `PT_LOAD` 段，见 `is_kernel_code`。
+/// 需要先调用 `memory::init_kernel_memory`。
+pub fn enforce_wx() -> WxReport {
+    enable_nxe();
+
+    let header = kernel_elf_header();
+    with_kernel_memory(|memory| {
+        let physical_memory_offset = memory.mapper.phys_offset();
+        let mut report = WxReport::default();
+        unsafe {
+            paging::update_mappings(physical_memory_offset, |mapping| {
+                let flags = mapping.flags;
+                let in_text = is_kernel_code(header, mapping.virt);
+                if in_text && flags.contains(PageTableFlags::WRITABLE) {
+                    report.text_pages += 1;
+                    Some(flags - PageTableFlags::WRITABLE - PageTableFlags::NO_EXECUTE)
+                } else if !in_text
+                    && flags.contains(PageTableFlags::WRITABLE)
+                    && !flags.contains(PageTableFlags::NO_EXECUTE)
+                {
+                    report.data_pages += 1;
+                    Some(flags | PageTableFlags::NO_EXECUTE)
+                } else {
+                    None
+                }
+            })
+        };
+        report
+    })
+    .expect("kernel memory not initialized")
+}
+
+extern "C" {
+    // 链接器 (ld.lld) 定义的符号，位于内核 ELF 文件头处。第一个 PT_LOAD 段从文件
+    // 开头开始，所以文件头和程序头都被 bootloader 加载到了内存中
+    static __ehdr_start: ElfHeader;
+}
+
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+
+/// ELF64 文件头。
+#[repr(C)]
+struct ElfHeader {
+    ident: [u8; 16],
+    kind: u16,
+    machine: u16,
+    version: u32,
+    entry: u64,
+    phoff: u64,
+    shoff: u64,
+    flags: u32,
+    ehsize: u16,
+    phentsize: u16,
+    phnum: u16,
+    shentsize: u16,
+    shnum: u16,
+    shstrndx: u16,
+}
+
+/// ELF64 程序头。
+#[repr(C)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+    align: u64,
+}
+
+fn kernel_elf_header() -> &'static ElfHeader {
+    let header = unsafe { &__ehdr_start };
+    assert!(
+        header.ident[..4] == ELF_MAGIC
+            && header.phentsize as usize == mem::size_of::<ProgramHeader>(),
+        "kernel ELF header is not mapped"
+    );
+    header
+}
+
+fn program_headers(header: &'static ElfHeader) -> &'static [ProgramHeader] {
+    let start = header as *const ElfHeader as usize + header.phoff as usize;
+    unsafe { slice::from_raw_parts(start as *const ProgramHeader, header.phnum as usize) }
+}
+
+/// `addr` 所在的页是否属于内核的某个可执行段。
+fn is_kernel_code(header: &'static ElfHeader, addr: VirtAddr) -> bool {
+    program_headers(header).iter().any(|segment| {
+        if segment.kind != PT_LOAD || segment.flags & PF_X == 0 {
+            return false;
+        }
+        let start = align_down(segment.vaddr, Size4KiB::SIZE);
+        let end = align_up(segment.vaddr + segment.memsz, Size4KiB::SIZE);
+        start <= addr.as_u64() && addr.as_u64() < end
+    })
+}
@@ -0,0 +1,118 @@
+use alloc::vec::Vec;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::fmt;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{PageTable, PageTableFlags, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+use super::{physical_memory_offset, with_kernel_memory};
+
+/// `reclaim_boot_memory` 交还给帧分配器的内存，以字节为单位。
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReclaimReport {
+    pub bootloader: u64,
+    pub boot_info: u64,
+    /// Frames of the bootloader's page tables that are no longer part of the
+    /// active page table hierarchy.
+    pub page_tables: u64,
+}
+
+impl ReclaimReport {
+    pub fn total(&self) -> u64 {
+        self.bootloader + self.boot_info + self.page_tables
+    }
+}
+
+impl fmt::Display for ReclaimReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "reclaimed {} KiB of boot memory (bootloader {} KiB, boot info {} KiB, page tables {} KiB)",
+            self.total() / 1024,
+            self.bootloader / 1024,
+            self.boot_info / 1024,
+            self.page_tables / 1024
+        )
+    }
+}
+
+/// 内核初始化完成之后，把 bootloader 占用但内核不再需要的内存交还给帧分配器:
+/// bootloader 本身、`BootInfo`，以及不在活动页表里的初始页表帧。
+///
+/// 这个函数是不安全的，因为调用者必须保证之后不再访问 `BootInfo` (包括
+/// `memory_map` 自己)，并且不再使用 bootloader 的 GDT 等数据结构。
+/// 需要先调用 `memory::init_kernel_memory` 并初始化堆。
+pub unsafe fn reclaim_boot_memory(memory_map: &'static MemoryMap) -> ReclaimReport {
+    let offset = physical_memory_offset().expect("kernel memory not initialized");
+    // 在内核内存锁之外收集，这里需要在堆上分配
+    let active_tables = active_tables(offset);
+
+    with_kernel_memory(|memory| {
+        let mut report = ReclaimReport::default();
+        // 释放帧只修改位图，不会写帧本身，所以可以一边释放一边读内存 map
+        for region in memory_map.iter() {
+            let start = region.range.start_addr();
+            let end = region.range.end_addr();
+            let counter = match region.region_type {
+                MemoryRegionType::Bootloader => &mut report.bootloader,
+                MemoryRegionType::BootInfo => &mut report.boot_info,
+                MemoryRegionType::PageTable => &mut report.page_tables,
+                _ => continue,
+            };
+
+            // 还在使用的页表帧不能释放，bootloader 的初始页表可能就在这些区域里
+            for frame in (start..end).step_by(4096) {
+                if active_tables.binary_search(&frame).is_err() {
+                    let start = PhysAddr::new(frame);
+                    let added = memory.frame_allocator.add_frames(start, start + 4096u64);
+                    *counter += added as u64 * 4096;
+                }
+            }
+        }
+        report
+    })
+    .expect("kernel memory not initialized")
+}
+
+/// 返回活动页表层级中所有页表所在帧的物理地址，按地址排序。
+unsafe fn active_tables(physical_memory_offset: VirtAddr) -> Vec<u64> {
+    let (level_4_frame, _) = Cr3::read();
+    let mut count = 0;
+    walk_tables(level_4_frame, 4, physical_memory_offset, &mut |_| {
+        count += 1
+    });
+
+    // 先分配好，遍历的时候堆不会再扩展、修改正在读的页表；分配本身可能多建几张表
+    let mut tables = Vec::with_capacity(count + 8);
+    walk_tables(level_4_frame, 4, physical_memory_offset, &mut |table| {
+        tables.push(table.start_address().as_u64())
+    });
+    tables.sort_unstable();
+    tables
+}
+
+unsafe fn walk_tables(
+    table: PhysFrame,
+    level: u8,
+    physical_memory_offset: VirtAddr,
+    f: &mut impl FnMut(PhysFrame),
+) {
+    f(table);
+    if level == 1 {
+        return;
+    }
+    let ptr: *const PageTable = (physical_memory_offset + table.start_address().as_u64()).as_ptr();
+    for entry in (*ptr).iter() {
+        let flags = entry.flags();
+        if flags.contains(PageTableFlags::PRESENT) && !flags.contains(PageTableFlags::HUGE_PAGE) {
+            walk_tables(
+                PhysFrame::containing_address(entry.addr()),
+                level - 1,
+                physical_memory_offset,
+                f,
+            );
+        }
+    }
+}
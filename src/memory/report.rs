@@ -0,0 +1,92 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use x86_64::VirtAddr;
+
+use super::{mmio, stack};
+use crate::allocator::{HEAP_MAX_SIZE, HEAP_START};
+use crate::serial_println;
+
+// 不同类型的个数上限，Unknown 类型的每个值各占一项
+const MAX_TYPES: usize = 32;
+
+/// 某一种类型的内存区域的合计。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TypeTotal {
+    pub region_type: MemoryRegionType,
+    pub regions: usize,
+    pub bytes: u64,
+}
+
+/// 按类型合计内存 map 中的区域，按第一次出现的顺序排列。
+pub fn totals_by_type(memory_map: &MemoryMap) -> impl Iterator<Item = TypeTotal> {
+    let mut totals: [Option<TypeTotal>; MAX_TYPES] = [None; MAX_TYPES];
+    for region in memory_map.iter() {
+        let size = region.range.end_addr() - region.range.start_addr();
+        let slot = totals
+            .iter_mut()
+            .find(|slot| slot.map_or(true, |total| total.region_type == region.region_type));
+        if let Some(slot) = slot {
+            let total = slot.get_or_insert(TypeTotal {
+                region_type: region.region_type,
+                regions: 0,
+                bytes: 0,
+            });
+            total.regions += 1;
+            total.bytes += size;
+        }
+    }
+    totals.into_iter().flatten()
+}
+
+/// 通过串口打印 bootloader 交给内核的内存 map (每个区域、按类型的合计)
+/// 以及内核自己的虚拟地址布局。
+pub fn print_boot_report(memory_map: &MemoryMap, physical_memory_offset: VirtAddr) {
+    serial_println!("memory map:");
+    serial_println!("  physical range                       size type");
+    for region in memory_map.iter() {
+        let start = region.range.start_addr();
+        let end = region.range.end_addr();
+        serial_println!(
+            "  {:#014x}-{:#014x} {:>10} KiB {:?}",
+            start,
+            end,
+            (end - start) / 1024,
+            region.region_type
+        );
+    }
+
+    serial_println!("totals by type:");
+    let mut usable = 0;
+    for total in totals_by_type(memory_map) {
+        if total.region_type == MemoryRegionType::Usable {
+            usable = total.bytes;
+        }
+        serial_println!(
+            "  {:>4} regions {:>10} KiB {:?}",
+            total.regions,
+            total.bytes / 1024,
+            total.region_type
+        );
+    }
+    serial_println!("  usable memory: {} MiB", usable / 1024 / 1024);
+
+    serial_println!("kernel layout:");
+    serial_println!(
+        "  heap             {:#018x}-{:#018x}",
+        HEAP_START,
+        HEAP_START + HEAP_MAX_SIZE
+    );
+    serial_println!(
+        "  kernel stacks    {:#018x}-{:#018x}",
+        stack::KERNEL_STACKS_START,
+        stack::KERNEL_STACKS_START + stack::KERNEL_STACKS_SIZE
+    );
+    serial_println!(
+        "  mmio window      {:#018x}-{:#018x}",
+        mmio::MMIO_START,
+        mmio::MMIO_START + mmio::MMIO_SIZE
+    );
+    serial_println!(
+        "  physical memory  {:#018x}",
+        physical_memory_offset.as_u64()
+    );
+}
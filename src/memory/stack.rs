@@ -0,0 +1,117 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, FrameDeallocator, Mapper, Page, PageSize,
+        PageTableFlags, Size4KiB,
+    },
+    VirtAddr,
+};
+
+use super::{with_kernel_memory, KernelMemory};
+
+/// 内核栈专用的虚拟地址区域。
+pub const KERNEL_STACKS_START: u64 = 0x_5555_5555_0000;
+pub const KERNEL_STACKS_SIZE: u64 = 1024 * 1024 * 1024; // 1 GiB
+
+// 下一个栈 (包括它的 guard page) 的起始地址
+static NEXT_STACK: AtomicU64 = AtomicU64::new(KERNEL_STACKS_START);
+
+/// 一个内核栈的地址范围，不包括它下面的 guard page。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackBounds {
+    pub bottom: VirtAddr,
+    /// The initial stack pointer, stacks grow down from here.
+    pub top: VirtAddr,
+}
+
+impl StackBounds {
+    /// The unmapped page directly below the stack.
+    pub fn guard_page(&self) -> Page {
+        Page::containing_address(self.bottom - 1u64)
+    }
+}
+
+#[derive(Debug)]
+pub enum StackError {
+    /// The kernel stack region has no room left for a stack of this size.
+    RegionFull,
+    Map(MapToError<Size4KiB>),
+}
+
+impl From<MapToError<Size4KiB>> for StackError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        StackError::Map(err)
+    }
+}
+
+/// 在内核栈区域里分配一个 `pages` 页大小的栈，下面留一个不映射的 guard page，
+/// 栈溢出时触发 page fault 而不是悄悄覆盖其它内存。
+///
+/// 栈不会被释放。需要先调用 `memory::init_kernel_memory`。
+pub fn alloc_stack(pages: u64) -> Result<StackBounds, StackError> {
+    let size = pages
+        .checked_add(1)
+        .and_then(|pages| pages.checked_mul(Size4KiB::SIZE))
+        .filter(|&size| size <= KERNEL_STACKS_SIZE)
+        .ok_or(StackError::RegionFull)?;
+    // 放得下时才移动 NEXT_STACK，失败的分配不占用虚拟地址
+    let guard = NEXT_STACK
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |next| {
+            next.checked_add(size)
+                .filter(|&end| end <= KERNEL_STACKS_START + KERNEL_STACKS_SIZE)
+        })
+        .map_err(|_| StackError::RegionFull)?;
+
+    let bottom = VirtAddr::new(guard + Size4KiB::SIZE);
+    let top = VirtAddr::new(guard + size);
+    let mapped = with_kernel_memory(|memory| {
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        let first = Page::containing_address(bottom);
+        for page in Page::range(first, Page::containing_address(top)) {
+            if let Err(err) = map_stack_page(memory, page, flags) {
+                // 取消已经映射的页并释放它们的帧
+                for mapped in Page::range(first, page) {
+                    if let Some((phys, page_size)) = memory.unmap_page(mapped.start_address()) {
+                        unsafe { memory.free_frames(phys, page_size) };
+                    }
+                }
+                return Err(err);
+            }
+        }
+        Ok(())
+    })
+    .expect("kernel memory not initialized");
+
+    if let Err(err) = mapped {
+        // 之后没有别的栈分配时把虚拟地址也还回去
+        let _ =
+            NEXT_STACK.compare_exchange(guard + size, guard, Ordering::Relaxed, Ordering::Relaxed);
+        return Err(err.into());
+    }
+    Ok(StackBounds { bottom, top })
+}
+
+fn map_stack_page(
+    memory: &mut KernelMemory,
+    page: Page,
+    flags: PageTableFlags,
+) -> Result<(), MapToError<Size4KiB>> {
+    let frame = memory
+        .frame_allocator
+        .allocate_frame()
+        .ok_or(MapToError::FrameAllocationFailed)?;
+    match unsafe {
+        memory
+            .mapper
+            .map_to(page, frame, flags, &mut memory.frame_allocator)
+    } {
+        Ok(flush) => {
+            flush.flush();
+            Ok(())
+        }
+        Err(err) => {
+            unsafe { memory.frame_allocator.deallocate_frame(frame) };
+            Err(err)
+        }
+    }
+}
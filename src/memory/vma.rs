@@ -0,0 +1,132 @@
+use alloc::vec::Vec;
+use core::ptr;
+use spin::Mutex;
+use x86_64::{
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, Page, PageSize, PageTableFlags, Size4KiB,
+    },
+    VirtAddr,
+};
+
+use super::{address_space, with_kernel_memory, with_kernel_memory_in_fault};
+
+/// 一段预留的虚拟地址区域，第一次访问某个页时才分配物理帧。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Vma {
+    pub start: VirtAddr,
+    pub end: VirtAddr,
+    /// Flags the pages are mapped with once they are touched.
+    pub flags: PageTableFlags,
+}
+
+impl Vma {
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end
+    }
+
+    fn overlaps(&self, other: &Vma) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmaError {
+    /// Start or size are not multiples of the page size.
+    Unaligned,
+    Empty,
+    /// The range overlaps an already reserved region.
+    Overlap,
+}
+
+static VMAS: Mutex<Vec<Vma>> = Mutex::new(Vec::new());
+
+/// 预留 `start..start + size` 这段虚拟地址，访问时由缺页处理分配清零的帧，
+/// 并用 `flags` 映射。可写的区域总是加上 `NO_EXECUTE` (W^X)。
+pub fn reserve_lazy(start: VirtAddr, size: u64, flags: PageTableFlags) -> Result<Vma, VmaError> {
+    if !start.is_aligned(Size4KiB::SIZE) || size % Size4KiB::SIZE != 0 {
+        return Err(VmaError::Unaligned);
+    }
+    if size == 0 {
+        return Err(VmaError::Empty);
+    }
+
+    let mut flags = flags | PageTableFlags::PRESENT;
+    if flags.contains(PageTableFlags::WRITABLE) {
+        flags |= PageTableFlags::NO_EXECUTE;
+    }
+    let vma = Vma {
+        start,
+        end: start + size,
+        flags,
+    };
+    let mut vmas = VMAS.lock();
+    if vmas.iter().any(|other| other.overlaps(&vma)) {
+        return Err(VmaError::Overlap);
+    }
+    vmas.push(vma);
+    Ok(vma)
+}
+
+/// 取消从 `start` 开始的预留区域，释放已经映射的帧。
+pub fn release(start: VirtAddr) -> Option<Vma> {
+    let vma = {
+        let mut vmas = VMAS.lock();
+        let index = vmas.iter().position(|vma| vma.start == start)?;
+        vmas.swap_remove(index)
+    };
+
+    with_kernel_memory(|memory| {
+        let last_page = Page::<Size4KiB>::containing_address(vma.end - 1u64);
+        for page in Page::range_inclusive(Page::containing_address(vma.start), last_page) {
+            // 没有被访问过的页还没有映射
+            if let Ok((frame, flush)) = memory.mapper.unmap(page) {
+                flush.flush();
+                unsafe { memory.frame_allocator.deallocate_frame(frame) };
+            }
+        }
+    });
+    Some(vma)
+}
+
+/// 缺页处理: `addr` 落在某个预留区域里时，分配一个清零的帧映射到该页并返回 true。
+///
+/// 页映射在当前 `Cr3` 指向的页表里。不使用堆；缺页发生在持有 VMA 锁或者内核内存锁
+/// 的代码里时 panic。
+pub fn handle_page_fault(addr: VirtAddr) -> bool {
+    let vma = match VMAS
+        .try_lock()
+        .expect("page fault while the VMA lock is held")
+        .iter()
+        .find(|vma| vma.contains(addr))
+    {
+        Some(vma) => *vma,
+        None => return false,
+    };
+    let page = Page::<Size4KiB>::containing_address(addr);
+
+    with_kernel_memory_in_fault(|memory| {
+        // 内核地址映射在共享的页表里，内核和其它地址空间都能看到
+        address_space::sync_kernel_slot(memory, addr, true);
+        memory.with_active_mapper(|mapper, frame_allocator| {
+            let frame = match frame_allocator.allocate_frame() {
+                Some(frame) => frame,
+                None => return false,
+            };
+            // 通过物理内存偏移清零，区域本身可能是只读的
+            let frame_ptr = mapper.phys_offset() + frame.start_address().as_u64();
+            unsafe { ptr::write_bytes(frame_ptr.as_mut_ptr::<u8>(), 0, Size4KiB::SIZE as usize) };
+
+            match unsafe { mapper.map_to(page, frame, vma.flags, frame_allocator) } {
+                Ok(flush) => {
+                    flush.flush();
+                    true
+                }
+                Err(_) => {
+                    unsafe { frame_allocator.deallocate_frame(frame) };
+                    false
+                }
+            }
+        })
+    })
+    .unwrap_or(false)
+}
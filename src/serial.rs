@@ -3,14 +3,35 @@ use spin::Mutex;
 // 将测试结果传回宿主主机
 use uart_16550::SerialPort;
 
+const COM1: u16 = 0x3F8;
+// Line Status Register，bit 0 表示有收到的数据
+const LINE_STATUS: u16 = COM1 + 5;
+
 lazy_static! {
     pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
         serial_port.init();
         Mutex::new(serial_port)
     };
 }
 
+/// 不等待地从 COM1 读一个收到的字节，没有数据时返回 `None`。
+///
+/// `SerialPort::receive` 会一直等到有数据，不能在中断处理函数里使用。
+pub fn try_receive() -> Option<u8> {
+    use x86_64::instructions::{interrupts, port::Port};
+
+    interrupts::without_interrupts(|| {
+        let _serial = SERIAL1.lock();
+        unsafe {
+            if Port::<u8>::new(LINE_STATUS).read() & 1 == 0 {
+                return None;
+            }
+            Some(Port::<u8>::new(COM1).read())
+        }
+    })
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
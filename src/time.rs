@@ -0,0 +1,118 @@
+//! 单调时钟: PIT 每次中断前进一个 tick，更精确的时间用 TSC 测量，墙上时间来自 RTC。
+
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+
+use crate::interrupts::irq;
+
+mod hpet;
+pub mod pit;
+pub mod rtc;
+pub mod tsc;
+
+/// `init` 设置的默认 tick 频率 (Hz)。
+pub const DEFAULT_TICK_FREQUENCY: u32 = 100;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+// 启动以来经过的 PIT 输入时钟周期数，频率改变后 uptime 仍然准确
+static PIT_CYCLES: AtomicU64 = AtomicU64::new(0);
+
+/// 把 PIT 设置为 `DEFAULT_TICK_FREQUENCY`，开始计数，并从 RTC 读取墙上时间。
+pub fn init() {
+    set_tick_frequency(DEFAULT_TICK_FREQUENCY);
+    // 时钟的处理函数一直保留，不需要句柄
+    let _ = irq::register(irq::TIMER, &timer_tick).expect("IRQ line full");
+    rtc::sync();
+}
+
+/// 修改 tick 频率，返回实际的频率。
+pub fn set_tick_frequency(hz: u32) -> u32 {
+    pit::set_frequency(hz)
+}
+
+/// 当前的 tick 频率 (Hz)。
+pub fn tick_frequency() -> u32 {
+    pit::frequency()
+}
+
+/// 启动以来的 tick 数。
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// 启动以来经过的时间，精度为一个 tick。
+pub fn uptime() -> Duration {
+    let cycles = PIT_CYCLES.load(Ordering::Relaxed) as u128;
+    let nanos = cycles * 1_000_000_000 / pit::BASE_FREQUENCY as u128;
+    Duration::from_nanos(nanos as u64)
+}
+
+fn timer_tick() -> bool {
+    PIT_CYCLES.fetch_add(pit::divisor() as u64, Ordering::Relaxed);
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+/// 用 TSC 测量的单调时间点，精度远高于 tick。
+///
+/// 和 `std::time::Instant` 一样，只能和其它 `Instant` 比较。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant(tsc::read())
+    }
+
+    /// 从 `earlier` 到 `self` 经过的时间，`earlier` 更晚时返回 0。
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        cycles_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        self.0
+            .checked_add(duration_to_cycles(duration)?)
+            .map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+fn cycles_to_duration(cycles: u64) -> Duration {
+    let nanos = cycles as u128 * 1_000_000_000 / tsc::frequency() as u128;
+    Duration::from_nanos(nanos as u64)
+}
+
+fn duration_to_cycles(duration: Duration) -> Option<u64> {
+    u64::try_from(duration.as_nanos() * tsc::frequency() as u128 / 1_000_000_000).ok()
+}
+
+/// 忙等 `duration`，不依赖中断，可以在关中断时使用。
+///
+/// TSC 表示不了的时间 (比如 `Duration::MAX`) 永远不会返回。
+pub fn delay(duration: Duration) {
+    let deadline = Instant::now().checked_add(duration);
+    while deadline.is_none_or(|deadline| Instant::now() < deadline) {
+        core::hint::spin_loop();
+    }
+}
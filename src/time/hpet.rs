@@ -0,0 +1,87 @@
+//! HPET (High Precision Event Timer) 的主计数器，只用来校准 TSC。
+
+use core::ptr;
+use x86_64::VirtAddr;
+
+use crate::{acpi, memory};
+
+const CAPABILITIES: u64 = 0x00;
+const CONFIG: u64 = 0x10;
+const MAIN_COUNTER: u64 = 0xf0;
+const CONFIG_ENABLE: u64 = 1;
+// 置位时主计数器是 64 位的，否则只有低 32 位有效
+const COUNT_SIZE_CAP: u64 = 1 << 13;
+const REGISTERS_SIZE: u64 = 0x400;
+
+/// 映射好的 HPET 寄存器，drop 时取消映射。
+pub struct Hpet {
+    registers: VirtAddr,
+    period_fs: u64,
+    wide: bool,
+}
+
+impl Hpet {
+    /// 通过 ACPI 找到 HPET，映射寄存器并打开主计数器。
+    ///
+    /// 需要先初始化内核内存和堆。
+    pub fn open() -> Option<Hpet> {
+        let physical_memory_offset = memory::physical_memory_offset()?;
+        let info = unsafe { acpi::hpet(physical_memory_offset) }?;
+        let registers = memory::mmio::map_mmio(info.address, REGISTERS_SIZE).ok()?;
+        let mut hpet = Hpet {
+            registers,
+            period_fs: 0,
+            wide: false,
+        };
+        // 高 32 位是计数器周期 (飞秒)，规范要求不超过 100 ns
+        let capabilities = hpet.read(CAPABILITIES);
+        hpet.period_fs = capabilities >> 32;
+        hpet.wide = capabilities & COUNT_SIZE_CAP != 0;
+        if hpet.period_fs == 0 || hpet.period_fs > 100_000_000 {
+            return None;
+        }
+        let config = hpet.read(CONFIG);
+        hpet.write(CONFIG, config | CONFIG_ENABLE);
+        Some(hpet)
+    }
+
+    /// 主计数器每次加一经过的飞秒数。
+    pub fn period_fs(&self) -> u64 {
+        self.period_fs
+    }
+
+    pub fn counter(&self) -> u64 {
+        let counter = self.read(MAIN_COUNTER);
+        if self.wide {
+            counter
+        } else {
+            counter as u32 as u64
+        }
+    }
+
+    /// 从计数器值 `start` 到现在经过的周期数，按计数器的宽度处理回绕。
+    ///
+    /// 32 位的计数器最快几十秒就会回绕，只能测量比这短的时间。
+    pub fn elapsed_since(&self, start: u64) -> u64 {
+        let now = self.counter();
+        if self.wide {
+            now.wrapping_sub(start)
+        } else {
+            (now as u32).wrapping_sub(start as u32) as u64
+        }
+    }
+
+    fn read(&self, offset: u64) -> u64 {
+        unsafe { ptr::read_volatile((self.registers + offset).as_ptr::<u64>()) }
+    }
+
+    fn write(&self, offset: u64, value: u64) {
+        unsafe { ptr::write_volatile((self.registers + offset).as_mut_ptr::<u64>(), value) }
+    }
+}
+
+impl Drop for Hpet {
+    fn drop(&mut self) {
+        let _ = memory::mmio::unmap_mmio(self.registers);
+    }
+}
@@ -0,0 +1,77 @@
+//! 8253/8254 可编程间隔定时器 (PIT) 的通道 0，输出接在 IRQ 0 上。
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+/// PIT 的输入时钟频率 (Hz)。
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+const CHANNEL0: u16 = 0x40;
+const COMMAND: u16 = 0x43;
+// 通道 0，先写低字节再写高字节，模式 2 (rate generator)，二进制计数
+const CMD_CHANNEL0_RATE: u8 = 0b0011_0100;
+
+// 分频值，写入 0 表示 65536，也是上电后的默认值 (约 18.2 Hz)
+const MAX_DIVISOR: u32 = 0x1_0000;
+// 模式 2 不能使用 1
+const MIN_DIVISOR: u32 = 2;
+
+static DIVISOR: AtomicU32 = AtomicU32::new(MAX_DIVISOR);
+
+/// 把通道 0 设置为尽量接近 `hz` 的频率，返回实际的频率。
+///
+/// 可用的范围大约是 19 Hz 到 596 kHz，超出的部分会被截断。
+pub fn set_frequency(hz: u32) -> u32 {
+    let divisor = (BASE_FREQUENCY + hz.max(1) / 2) / hz.max(1);
+    let divisor = divisor.clamp(MIN_DIVISOR, MAX_DIVISOR);
+    without_interrupts(|| {
+        let mut command = Port::<u8>::new(COMMAND);
+        let mut channel0 = Port::<u8>::new(CHANNEL0);
+        unsafe {
+            command.write(CMD_CHANNEL0_RATE);
+            channel0.write(divisor as u8);
+            channel0.write((divisor >> 8) as u8);
+        }
+        DIVISOR.store(divisor, Ordering::Relaxed);
+    });
+    frequency()
+}
+
+/// 当前的中断频率 (Hz，四舍五入)。
+pub fn frequency() -> u32 {
+    let divisor = divisor();
+    (BASE_FREQUENCY + divisor / 2) / divisor
+}
+
+/// 当前的分频值，即两次中断之间输入时钟的周期数。
+pub fn divisor() -> u32 {
+    DIVISOR.load(Ordering::Relaxed)
+}
+
+const CHANNEL2: u16 = 0x42;
+// 0x61 端口: bit 0 控制通道 2 的 gate，bit 1 打开扬声器，bit 5 是通道 2 的输出
+const SPEAKER_PORT: u16 = 0x61;
+// 通道 2，先写低字节再写高字节，模式 0 (interrupt on terminal count)
+const CMD_CHANNEL2_ONESHOT: u8 = 0b1011_0000;
+
+/// 用通道 2 忙等 `cycles` 个输入时钟周期 (最多 65535)，不影响通道 0 的中断。
+///
+/// 扬声器保持关闭。调用者应当关闭中断，否则等待的时间会更长。
+pub fn wait_cycles(cycles: u16) {
+    let mut speaker = Port::<u8>::new(SPEAKER_PORT);
+    let mut command = Port::<u8>::new(COMMAND);
+    let mut channel2 = Port::<u8>::new(CHANNEL2);
+    unsafe {
+        // 先关掉 gate，写入计数后再打开，计数从这里开始
+        let control = speaker.read() & !0b11;
+        speaker.write(control);
+        command.write(CMD_CHANNEL2_ONESHOT);
+        channel2.write(cycles as u8);
+        channel2.write((cycles >> 8) as u8);
+        speaker.write(control | 1);
+        while speaker.read() & (1 << 5) == 0 {
+            core::hint::spin_loop();
+        }
+        speaker.write(control);
+    }
+}
@@ -0,0 +1,322 @@
+//! CMOS 实时时钟 (RTC)，提供日期时间和可选的周期中断 (IRQ 8)。
+
+use core::fmt;
+use core::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+use crate::acpi;
+use crate::interrupts::irq::{self, IrqError, IrqHandle};
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+const REG_STATUS_C: u8 = 0x0c;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_A_RATE_MASK: u8 = 0x0f;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_PERIODIC: u8 = 1 << 6;
+const STATUS_C_PERIODIC: u8 = 1 << 6;
+const HOUR_PM: u8 = 1 << 7;
+
+// 周期中断的频率是 32768 >> (rate - 1)，rate 1 和 2 在标准晶振下不能用
+const MIN_RATE: u8 = 3;
+const MAX_RATE: u8 = 15;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+// 索引端口和数据端口必须成对访问
+static CMOS: Mutex<()> = Mutex::new(());
+
+/// 日期和时间，RTC 里没有时区信息，按 UTC 处理。
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// 从 Unix 时间戳转换。
+    pub fn from_unix_timestamp(timestamp: u64) -> DateTime {
+        let days = timestamp / SECONDS_PER_DAY;
+        let seconds = timestamp % SECONDS_PER_DAY;
+        let (year, month, day) = civil_from_days(days);
+        DateTime {
+            year,
+            month,
+            day,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+
+    /// 从 1970-01-01 00:00:00 UTC 开始的秒数。
+    pub fn unix_timestamp(&self) -> u64 {
+        days_from_civil(self.year, self.month, self.day) * SECONDS_PER_DAY
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+// 公历日期和 1970-01-01 之间的天数互相转换，只处理 1970 年以后
+// (算法来自 Howard Hinnant 的 chrono-Compatible Low-Level Date Algorithms)
+fn days_from_civil(year: u16, month: u8, day: u8) -> u64 {
+    let year = year as u64 - (month <= 2) as u64;
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let month_from_march = (month as u64 + 9) % 12;
+    let day_of_year = (153 * month_from_march + 2) / 5 + day as u64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: u64) -> (u16, u8, u8) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as u64;
+    (year as u16, month as u8, day as u8)
+}
+
+// 调用者需要持有 CMOS 锁并且关闭中断
+unsafe fn read_register(register: u8) -> u8 {
+    Port::<u8>::new(CMOS_INDEX).write(register);
+    Port::<u8>::new(CMOS_DATA).read()
+}
+
+unsafe fn write_register(register: u8, value: u8) {
+    Port::<u8>::new(CMOS_INDEX).write(register);
+    Port::<u8>::new(CMOS_DATA).write(value);
+}
+
+fn with_cmos<R>(f: impl FnOnce() -> R) -> R {
+    without_interrupts(|| {
+        let _guard = CMOS.lock();
+        f()
+    })
+}
+
+// FADT 给出的世纪寄存器，0 表示没有。内核内存初始化之前查不了 ACPI 表，
+// 这时是 CENTURY_UNKNOWN
+const CENTURY_UNKNOWN: u16 = u16::MAX;
+static CENTURY_REGISTER: AtomicU16 = AtomicU16::new(CENTURY_UNKNOWN);
+
+fn century_register() -> Option<u8> {
+    let mut register = CENTURY_REGISTER.load(Ordering::Relaxed);
+    if register == CENTURY_UNKNOWN {
+        let physical_memory_offset = crate::memory::physical_memory_offset()?;
+        register = unsafe { acpi::century_register(physical_memory_offset) }.map_or(0, u16::from);
+        CENTURY_REGISTER.store(register, Ordering::Relaxed);
+    }
+    match register {
+        0 => None,
+        register => Some(register as u8),
+    }
+}
+
+// RTC 寄存器中的原始值 (世纪寄存器可能没有)，格式由 status B 决定
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawTime([u8; 6], Option<u8>);
+
+unsafe fn read_raw(century_register: Option<u8>) -> RawTime {
+    // 更新期间 (大约 2 ms) 读到的值可能不一致
+    while read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+    RawTime(
+        [
+            read_register(REG_SECONDS),
+            read_register(REG_MINUTES),
+            read_register(REG_HOURS),
+            read_register(REG_DAY),
+            read_register(REG_MONTH),
+            read_register(REG_YEAR),
+        ],
+        century_register.map(|register| read_register(register)),
+    )
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+fn decode(raw: RawTime, status_b: u8) -> DateTime {
+    let RawTime([second, minute, hour, day, month, year], century) = raw;
+    let binary = |value: u8| {
+        if status_b & STATUS_B_BINARY != 0 {
+            value
+        } else {
+            from_bcd(value)
+        }
+    };
+
+    // 12 小时制时最高位表示下午，12 点表示 0 点或者中午
+    let mut hour24 = binary(hour & !HOUR_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        hour24 %= 12;
+        if hour & HOUR_PM != 0 {
+            hour24 += 12;
+        }
+    }
+
+    let year = binary(year) as u16;
+    let year = match century {
+        Some(century) => binary(century) as u16 * 100 + year,
+        // 没有世纪寄存器，假设年份在 1970 到 2069 之间
+        None if year < 70 => 2000 + year,
+        None => 1900 + year,
+    };
+    DateTime {
+        year,
+        month: binary(month),
+        day: binary(day),
+        hour: hour24,
+        minute: binary(minute),
+        second: binary(second),
+    }
+}
+
+/// 读取 RTC 当前的日期和时间。
+///
+/// 连续读两次直到结果相同，避免读到一半时 RTC 正好更新。世纪来自 FADT 给出的
+/// CMOS 寄存器，内核内存初始化之前或者 FADT 没有提供时假设是 1970 到 2069 年。
+pub fn read() -> DateTime {
+    let century_register = century_register();
+    let (raw, status_b) = with_cmos(|| unsafe {
+        let mut raw = read_raw(century_register);
+        loop {
+            let again = read_raw(century_register);
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        (raw, read_register(REG_STATUS_B))
+    });
+    decode(raw, status_b)
+}
+
+// 同步时的 Unix 时间戳 (秒) 和当时的 uptime (纳秒)
+static SYNC_TIMESTAMP: AtomicU64 = AtomicU64::new(0);
+static SYNC_UPTIME: AtomicU64 = AtomicU64::new(0);
+
+/// 读取 RTC，之后的 `now` 用单调时钟在这个时间上累加。
+pub fn sync() -> DateTime {
+    let time = read();
+    without_interrupts(|| {
+        SYNC_UPTIME.store(super::uptime().as_nanos() as u64, Ordering::Relaxed);
+        SYNC_TIMESTAMP.store(time.unix_timestamp(), Ordering::Relaxed);
+    });
+    time
+}
+
+/// 当前的墙上时间，从 Unix 纪元开始计算。
+///
+/// 精度取决于 tick 频率，RTC 只有秒的精度，所以和真实时间可能差一秒以内。
+pub fn now() -> Duration {
+    let (timestamp, synced_at) = without_interrupts(|| {
+        (
+            SYNC_TIMESTAMP.load(Ordering::Relaxed),
+            SYNC_UPTIME.load(Ordering::Relaxed),
+        )
+    });
+    Duration::from_secs(timestamp) + super::uptime() - Duration::from_nanos(synced_at)
+}
+
+static PERIODIC_HANDLE: Mutex<Option<IrqHandle>> = Mutex::new(None);
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// 打开 RTC 的周期中断，频率取不超过 `hz` 的最大可用值 (2 Hz 到 8192 Hz)，
+/// 返回实际的频率。`irq::RTC` 上没有空位注册处理函数时返回错误。
+///
+/// 其它模块可以在 `irq::RTC` 上注册自己的处理函数，中断由这里确认。
+pub fn enable_periodic(hz: u32) -> Result<u32, IrqError> {
+    let rate = (MIN_RATE..=MAX_RATE)
+        .find(|&rate| periodic_frequency(rate) <= hz)
+        .unwrap_or(MAX_RATE);
+
+    let mut handle = PERIODIC_HANDLE.lock();
+    if handle.is_none() {
+        *handle = Some(irq::register(irq::RTC, &periodic_interrupt)?);
+    }
+    with_cmos(|| unsafe {
+        let status_a = read_register(REG_STATUS_A);
+        write_register(REG_STATUS_A, (status_a & !STATUS_A_RATE_MASK) | rate);
+        let status_b = read_register(REG_STATUS_B);
+        write_register(REG_STATUS_B, status_b | STATUS_B_PERIODIC);
+        // 清除已经挂起的中断，否则 RTC 不会再发出中断
+        read_register(REG_STATUS_C);
+    });
+    Ok(periodic_frequency(rate))
+}
+
+/// 关闭周期中断并注销处理函数。
+pub fn disable_periodic() {
+    with_cmos(|| unsafe {
+        let status_b = read_register(REG_STATUS_B);
+        write_register(REG_STATUS_B, status_b & !STATUS_B_PERIODIC);
+        read_register(REG_STATUS_C);
+    });
+    if let Some(handle) = PERIODIC_HANDLE.lock().take() {
+        irq::unregister(handle);
+    }
+}
+
+/// 收到的周期中断次数。
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+fn periodic_frequency(rate: u8) -> u32 {
+    32768 >> (rate - 1)
+}
+
+fn periodic_interrupt() -> bool {
+    // 必须读 status C，RTC 才会发出下一个中断
+    let status_c = unsafe {
+        let _guard = CMOS.lock();
+        read_register(REG_STATUS_C)
+    };
+    if status_c & STATUS_C_PERIODIC == 0 {
+        return false;
+    }
+    PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+    true
+}
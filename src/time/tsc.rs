@@ -0,0 +1,116 @@
+//! 时间戳计数器 (TSC)，用 HPET 或者 PIT 校准频率。
+
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
+
+use super::{hpet::Hpet, pit};
+
+// 校准时测量的时间
+const CALIBRATION_MS: u64 = 10;
+
+// TSC 的频率 (Hz)，0 表示还没有校准
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// 校准 TSC 时使用的参考时钟。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reference {
+    Hpet,
+    Pit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Calibration {
+    /// TSC frequency in Hz.
+    pub frequency: u64,
+    pub reference: Reference,
+    /// The TSC runs at a constant rate in all power states.
+    pub invariant: bool,
+}
+
+impl fmt::Display for Calibration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "TSC: {}.{:03} MHz, calibrated against {:?}{}",
+            self.frequency / 1_000_000,
+            self.frequency / 1_000 % 1_000,
+            self.reference,
+            if self.invariant { ", invariant" } else { "" }
+        )
+    }
+}
+
+/// 判断 TSC 是否是 invariant 的 (CPUID.80000007h:EDX[8])。
+pub fn is_invariant() -> bool {
+    // 较新的编译器里 __cpuid 不再是 unsafe 的
+    #[allow(unused_unsafe)]
+    let max_extended = unsafe { __cpuid(0x8000_0000) }.eax;
+    if max_extended < 0x8000_0007 {
+        return false;
+    }
+    #[allow(unused_unsafe)]
+    let result = unsafe { __cpuid(0x8000_0007) };
+    result.edx & (1 << 8) != 0
+}
+
+/// 读取 TSC。
+pub fn read() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// 测量 TSC 的频率。有 HPET 时用 HPET，否则用 PIT 的通道 2。
+///
+/// 使用 HPET 需要先初始化内核内存和堆。
+pub fn calibrate() -> Calibration {
+    let (frequency, reference) = match Hpet::open() {
+        Some(hpet) => (calibrate_hpet(&hpet), Reference::Hpet),
+        None => (measure_with_pit(), Reference::Pit),
+    };
+    FREQUENCY.store(frequency, Ordering::Relaxed);
+    Calibration {
+        frequency,
+        reference,
+        invariant: is_invariant(),
+    }
+}
+
+/// TSC 的频率 (Hz)。还没有校准时先用 PIT 校准一次。
+pub fn frequency() -> u64 {
+    match FREQUENCY.load(Ordering::Relaxed) {
+        0 => {
+            let frequency = measure_with_pit();
+            FREQUENCY.store(frequency, Ordering::Relaxed);
+            frequency
+        }
+        frequency => frequency,
+    }
+}
+
+fn calibrate_hpet(hpet: &Hpet) -> u64 {
+    let ticks = CALIBRATION_MS * 1_000_000_000_000 / hpet.period_fs();
+    let (tsc, elapsed) = without_interrupts(|| {
+        let start = hpet.counter();
+        let tsc_start = read();
+        let mut elapsed = 0;
+        while elapsed < ticks {
+            core::hint::spin_loop();
+            elapsed = hpet.elapsed_since(start);
+        }
+        (read() - tsc_start, elapsed)
+    });
+    let femtos = elapsed as u128 * hpet.period_fs() as u128;
+    (tsc as u128 * 1_000_000_000_000_000 / femtos) as u64
+}
+
+/// 用 PIT 的通道 2 测量 TSC 的频率 (Hz)，不修改已经校准的结果。
+pub fn measure_with_pit() -> u64 {
+    let cycles = (pit::BASE_FREQUENCY as u64 * CALIBRATION_MS / 1000) as u16;
+    let tsc = without_interrupts(|| {
+        let start = read();
+        pit::wait_cycles(cycles);
+        read() - start
+    });
+    tsc * pit::BASE_FREQUENCY as u64 / cycles as u64
+}
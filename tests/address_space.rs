@@ -0,0 +1,149 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use rust_os::allocator::HEAP_START;
+use rust_os::memory::address_space::{self, AddressSpace, AddressSpaceError, USER_START};
+use rust_os::memory::{self, mmio, paging};
+use x86_64::structures::paging::{Page, PageTableFlags};
+use x86_64::{PhysAddr, VirtAddr};
+
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+// init_kernel_memory 用掉的帧数
+static INIT_FRAMES: AtomicU64 = AtomicU64::new(0);
+
+fn phys_mem_offset() -> VirtAddr {
+    VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::Relaxed))
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::BitmapFrameAllocator;
+
+    rust_os::init();
+    PHYS_MEM_OFFSET.store(boot_info.physical_memory_offset, Ordering::Relaxed);
+    let mapper = unsafe { memory::init(phys_mem_offset()) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset()) };
+    let before = frame_allocator.used_frames();
+    memory::init_kernel_memory(mapper, frame_allocator);
+    INIT_FRAMES.store((used_frames() - before) as u64, Ordering::Relaxed);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn used_frames() -> usize {
+    memory::with_kernel_memory(|memory| memory.frame_allocator.used_frames()).unwrap()
+}
+
+#[test_case]
+fn address_spaces_are_independent() {
+    let page = Page::containing_address(VirtAddr::new(USER_START));
+    let mut a = AddressSpace::new().unwrap();
+    let mut b = AddressSpace::new().unwrap();
+    let frame_a = a.map_user(page, PageTableFlags::WRITABLE).unwrap();
+    let frame_b = b.map_user(page, PageTableFlags::WRITABLE).unwrap();
+    assert_ne!(frame_a, frame_b);
+    assert_eq!(
+        a.translate(page.start_address()),
+        Some(frame_a.start_address())
+    );
+
+    let ptr: *mut u64 = page.start_address().as_mut_ptr();
+    unsafe {
+        a.switch_to();
+        ptr.write_volatile(1);
+        b.switch_to();
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(2);
+        a.switch_to();
+        assert_eq!(ptr.read_volatile(), 1);
+    }
+    address_space::switch_to_kernel();
+
+    // the kernel page table does not contain the user page
+    let translation = unsafe { paging::translate(page.start_address(), phys_mem_offset()) };
+    assert_eq!(translation, None);
+}
+
+#[test_case]
+fn drop_frees_all_frames() {
+    let used = used_frames();
+    let mut space = AddressSpace::new().unwrap();
+    for i in 0..3 {
+        let page = Page::containing_address(VirtAddr::new(USER_START + i * 0x20_0000));
+        space.map_user(page, PageTableFlags::WRITABLE).unwrap();
+    }
+    assert!(used_frames() > used);
+    drop(space);
+    assert_eq!(used_frames(), used);
+}
+
+#[test_case]
+fn kernel_range_is_rejected() {
+    let mut space = AddressSpace::new().unwrap();
+    let page = Page::containing_address(VirtAddr::new(HEAP_START as u64));
+    assert!(matches!(
+        space.map_user(page, PageTableFlags::WRITABLE),
+        Err(AddressSpaceError::KernelRange)
+    ));
+}
+
+#[test_case]
+fn unused_kernel_slot_is_rejected() {
+    // 内核还没有用到这个 4 级表项，它也不属于用户地址空间
+    let mut space = AddressSpace::new().unwrap();
+    let page = Page::containing_address(VirtAddr::new(0x_2222_4000_0000));
+    assert!(matches!(
+        space.map_user(page, PageTableFlags::WRITABLE),
+        Err(AddressSpaceError::KernelRange)
+    ));
+}
+
+#[test_case]
+fn later_kernel_mappings_are_shared() {
+    let mut space = AddressSpace::new().unwrap();
+    let virt = mmio::map_mmio(PhysAddr::new(0xb8000), 4096).unwrap();
+    assert_eq!(space.translate(virt), Some(PhysAddr::new(0xb8000)));
+    mmio::unmap_mmio(virt).unwrap();
+    assert_eq!(space.translate(virt), None);
+}
+
+#[test_case]
+fn kernel_memory_init_allocates_few_page_tables() {
+    // 最多给内核栈和 MMIO 窗口各分配一个 3 级表
+    assert!(INIT_FRAMES.load(Ordering::Relaxed) <= 2);
+}
+
+#[test_case]
+fn new_kernel_slots_are_copied_on_fault() {
+    let space = AddressSpace::new().unwrap();
+    // 地址空间创建之后内核才用到这个 4 级表项
+    let addr = VirtAddr::new(0x_1111_0000_0000);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    memory::with_kernel_memory(|memory| memory.map_anonymous_page(addr, 4096, flags))
+        .unwrap()
+        .unwrap();
+
+    let ptr: *mut u64 = addr.as_mut_ptr();
+    unsafe {
+        ptr.write_volatile(3);
+        space.switch_to();
+        assert_eq!(ptr.read_volatile(), 3);
+    }
+    address_space::switch_to_kernel();
+}
@@ -0,0 +1,63 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::acpi;
+use rust_os::interrupts::apic;
+use rust_os::memory;
+use x86_64::PhysAddr;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::BitmapFrameAllocator;
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn madt_describes_qemu_apics() {
+    let offset = memory::physical_memory_offset().unwrap();
+    let madt = unsafe { acpi::madt(offset) }.expect("no MADT");
+    assert_eq!(madt.local_apic_address, PhysAddr::new(0xfee0_0000));
+    assert!(!madt.local_apic_ids.is_empty());
+    assert!(madt.io_apics.iter().any(|io_apic| io_apic.gsi_base == 0));
+}
+
+#[test_case]
+fn fadt_provides_the_century_register() {
+    // QEMU 的 FADT 把世纪放在 CMOS 寄存器 0x32
+    let offset = memory::physical_memory_offset().unwrap();
+    assert_eq!(unsafe { acpi::century_register(offset) }, Some(0x32));
+}
+
+#[test_case]
+fn timer_interrupts_arrive_through_apic() {
+    assert!(apic::local_apic_supported());
+    assert!(apic::init());
+    assert!(apic::is_enabled());
+    // 8259 已经被屏蔽，hlt 只能被 I/O APIC 转发的定时器中断唤醒
+    for _ in 0..3 {
+        x86_64::instructions::hlt();
+    }
+}
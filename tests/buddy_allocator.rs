@@ -0,0 +1,77 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::memory::buddy::{block_size, BuddyFrameAllocator, ORDER_2MIB};
+use spin::Mutex;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB};
+use x86_64::VirtAddr;
+
+static BUDDY: Mutex<Option<BuddyFrameAllocator>> = Mutex::new(None);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let buddy = unsafe { BuddyFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    *BUDDY.lock() = Some(buddy);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn blocks_are_aligned() {
+    let mut guard = BUDDY.lock();
+    let buddy = guard.as_mut().unwrap();
+    for order in 0..4 {
+        let frame = buddy.allocate(order).unwrap();
+        assert_eq!(frame.start_address().as_u64() % block_size(order), 0);
+        unsafe { buddy.deallocate(frame, order) };
+    }
+}
+
+#[test_case]
+fn huge_frame_allocation() {
+    let mut guard = BUDDY.lock();
+    let buddy = guard.as_mut().unwrap();
+    let free = buddy.free_frames();
+
+    let frame: PhysFrame<Size2MiB> = buddy.allocate_frame().unwrap();
+    assert!(frame.start_address().is_aligned(block_size(ORDER_2MIB)));
+    assert_eq!(buddy.free_frames(), free - 512);
+
+    unsafe { buddy.deallocate_frame(frame) };
+    assert_eq!(buddy.free_frames(), free);
+}
+
+#[test_case]
+fn buddies_merge_on_free() {
+    let mut guard = BUDDY.lock();
+    let buddy = guard.as_mut().unwrap();
+    let free = buddy.free_frames();
+    let single_blocks = buddy.free_blocks(0);
+
+    // free an order 1 block as two single frames
+    let first = buddy.allocate(1).unwrap();
+    let second = PhysFrame::containing_address(first.start_address() + block_size(0));
+    unsafe {
+        buddy.deallocate(first, 0);
+        assert_eq!(buddy.free_blocks(0), single_blocks + 1);
+        buddy.deallocate(second, 0);
+    }
+
+    // the halves were merged instead of staying in the order 0 list
+    assert_eq!(buddy.free_blocks(0), single_blocks);
+    assert_eq!(buddy.free_frames(), free);
+}
@@ -0,0 +1,210 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use rust_os::memory::{self, cow, paging};
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+use x86_64::VirtAddr;
+
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+fn phys_mem_offset() -> VirtAddr {
+    VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::Relaxed))
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::BitmapFrameAllocator;
+
+    rust_os::init();
+    PHYS_MEM_OFFSET.store(boot_info.physical_memory_offset, Ordering::Relaxed);
+    let mapper = unsafe { memory::init(phys_mem_offset()) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset()) };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+const COW_START: u64 = 0x_3333_0000_0000;
+
+fn map_page(addr: VirtAddr) {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    memory::with_kernel_memory(|memory| memory.map_anonymous_page(addr, 4096, flags))
+        .unwrap()
+        .unwrap();
+}
+
+fn frame_of(addr: VirtAddr) -> PhysFrame {
+    let translation = unsafe { paging::translate(addr, phys_mem_offset()) }.unwrap();
+    PhysFrame::containing_address(translation.phys)
+}
+
+fn flags_of(addr: VirtAddr) -> PageTableFlags {
+    unsafe { paging::translate(addr, phys_mem_offset()) }
+        .unwrap()
+        .flags
+}
+
+#[test_case]
+fn write_to_shared_page_copies_it() {
+    let a = VirtAddr::new(COW_START);
+    let b = VirtAddr::new(COW_START + 4096);
+    map_page(a);
+    let ptr_a: *mut u64 = a.as_mut_ptr();
+    let ptr_b: *mut u64 = b.as_mut_ptr();
+    unsafe { ptr_a.write_volatile(42) };
+
+    cow::share_page(Page::containing_address(a), Page::containing_address(b)).unwrap();
+    let frame = frame_of(a);
+    assert_eq!(frame_of(b), frame);
+    assert_eq!(cow::ref_count(frame), 2);
+    assert!(flags_of(a).contains(cow::COW));
+    assert!(!flags_of(b).contains(PageTableFlags::WRITABLE));
+
+    // the write faults and gives b its own copy
+    unsafe {
+        assert_eq!(ptr_b.read_volatile(), 42);
+        ptr_b.write_volatile(7);
+        assert_eq!(ptr_b.read_volatile(), 7);
+        assert_eq!(ptr_a.read_volatile(), 42);
+    }
+    assert_ne!(frame_of(b), frame);
+    assert!(flags_of(b).contains(PageTableFlags::WRITABLE));
+    assert_eq!(cow::ref_count(frame), 1);
+
+    // a is the last user of the frame, it only becomes writable again
+    unsafe { ptr_a.write_volatile(43) };
+    assert_eq!(frame_of(a), frame);
+    assert!(!flags_of(a).contains(cow::COW));
+    assert_eq!(unsafe { ptr_b.read_volatile() }, 7);
+}
+
+#[test_case]
+fn sharing_unmapped_page_fails() {
+    let src = Page::containing_address(VirtAddr::new(COW_START + 16 * 4096));
+    let dst = Page::containing_address(VirtAddr::new(COW_START + 17 * 4096));
+    assert!(matches!(
+        cow::share_page(src, dst),
+        Err(cow::CowError::NotMapped)
+    ));
+}
+
+#[test_case]
+fn write_fault_in_another_address_space() {
+    use rust_os::memory::address_space::{self, AddressSpace};
+
+    let a = VirtAddr::new(COW_START + 32 * 4096);
+    let b = VirtAddr::new(COW_START + 33 * 4096);
+    map_page(a);
+    cow::share_page(Page::containing_address(a), Page::containing_address(b)).unwrap();
+    let frame = frame_of(a);
+
+    // 缺页处理修改的是当前地址空间的页表，内核的映射在两边共享
+    let space = AddressSpace::new().unwrap();
+    let ptr_b: *mut u64 = b.as_mut_ptr();
+    unsafe {
+        space.switch_to();
+        ptr_b.write_volatile(9);
+        assert_eq!(ptr_b.read_volatile(), 9);
+    }
+    address_space::switch_to_kernel();
+
+    assert_ne!(frame_of(b), frame);
+    assert_eq!(unsafe { ptr_b.read_volatile() }, 9);
+    assert_eq!(cow::ref_count(frame), 1);
+}
+
+#[test_case]
+fn share_into_another_address_space() {
+    use rust_os::memory::address_space::{self, AddressSpace};
+
+    let a = VirtAddr::new(COW_START + 48 * 4096);
+    map_page(a);
+    let ptr_a: *mut u64 = a.as_mut_ptr();
+    unsafe { ptr_a.write_volatile(5) };
+    let frame = frame_of(a);
+
+    let mut space = AddressSpace::new().unwrap();
+    let dst = VirtAddr::new(address_space::USER_START);
+    cow::share_page_into(
+        Page::containing_address(a),
+        &mut space,
+        Page::containing_address(dst),
+    )
+    .unwrap();
+    assert_eq!(space.translate(dst), Some(frame.start_address()));
+    assert_eq!(cow::ref_count(frame), 2);
+
+    // 在那个地址空间里写入时得到自己的副本
+    let ptr_dst: *mut u64 = dst.as_mut_ptr();
+    unsafe {
+        space.switch_to();
+        assert_eq!(ptr_dst.read_volatile(), 5);
+        ptr_dst.write_volatile(6);
+    }
+    address_space::switch_to_kernel();
+    assert_ne!(space.translate(dst), Some(frame.start_address()));
+    assert_eq!(unsafe { ptr_a.read_volatile() }, 5);
+    assert_eq!(cow::ref_count(frame), 1);
+
+    // 只能共享到用户地址范围
+    assert!(matches!(
+        cow::share_page_into(
+            Page::containing_address(a),
+            &mut space,
+            Page::containing_address(a)
+        ),
+        Err(cow::CowError::KernelRange)
+    ));
+}
+
+#[test_case]
+fn dropping_an_address_space_releases_shared_frames() {
+    use rust_os::memory::address_space::{self, AddressSpace};
+
+    let a = VirtAddr::new(COW_START + 64 * 4096);
+    map_page(a);
+    let frame = frame_of(a);
+    let mut space = AddressSpace::new().unwrap();
+    let dst = Page::containing_address(VirtAddr::new(address_space::USER_START));
+    cow::share_page_into(Page::containing_address(a), &mut space, dst).unwrap();
+    assert_eq!(cow::ref_count(frame), 2);
+
+    drop(space);
+    assert_eq!(cow::ref_count(frame), 1);
+    assert_eq!(frame_of(a), frame);
+}
+
+#[test_case]
+fn many_frames_can_be_shared() {
+    // 共享的帧数只受物理内存限制
+    const PAGES: u64 = 2048;
+    let src = COW_START + 0x10_0000;
+    let dst = COW_START + 0x100_0000;
+    for i in 0..PAGES {
+        let addr = VirtAddr::new(src + i * 4096);
+        map_page(addr);
+        cow::share_page(
+            Page::containing_address(addr),
+            Page::containing_address(VirtAddr::new(dst + i * 4096)),
+        )
+        .unwrap();
+    }
+    let last = VirtAddr::new(src + (PAGES - 1) * 4096);
+    assert_eq!(cow::ref_count(frame_of(last)), 2);
+}
@@ -0,0 +1,89 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::memory::vma::{self, VmaError};
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BitmapFrameAllocator};
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+const LAZY_START: u64 = 0x_5555_0000_0000;
+const LATE_START: u64 = 0x_2222_0000_0000;
+
+#[test_case]
+fn lazy_pages_are_zeroed_and_writable() {
+    let start = VirtAddr::new(LAZY_START);
+    let flags = PageTableFlags::WRITABLE;
+    vma::reserve_lazy(start, 4 * 4096, flags).unwrap();
+
+    let ptr: *mut u64 = (start + 3 * 4096u64 + 8u64).as_mut_ptr();
+    unsafe {
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(0xdead_beef);
+        assert_eq!(ptr.read_volatile(), 0xdead_beef);
+    }
+
+    assert!(vma::release(start).is_some());
+}
+
+#[test_case]
+fn overlapping_reservation_fails() {
+    let start = VirtAddr::new(LAZY_START);
+    vma::reserve_lazy(start, 2 * 4096, PageTableFlags::WRITABLE).unwrap();
+    assert_eq!(
+        vma::reserve_lazy(start + 4096u64, 4096, PageTableFlags::WRITABLE),
+        Err(VmaError::Overlap)
+    );
+    assert_eq!(
+        vma::reserve_lazy(start + 1u64, 4096, PageTableFlags::WRITABLE),
+        Err(VmaError::Unaligned)
+    );
+    assert!(vma::release(start).is_some());
+}
+
+#[test_case]
+fn lazy_page_is_mapped_in_the_active_address_space() {
+    use rust_os::memory::address_space::{self, AddressSpace};
+
+    // 地址空间在预留之前创建，缺页要修改的是它的页表
+    let mut space = AddressSpace::new().unwrap();
+    let start = VirtAddr::new(LATE_START);
+    vma::reserve_lazy(start, 4096, PageTableFlags::WRITABLE).unwrap();
+
+    let ptr: *mut u64 = start.as_mut_ptr();
+    unsafe {
+        space.switch_to();
+        ptr.write_volatile(7);
+        assert_eq!(ptr.read_volatile(), 7);
+    }
+    address_space::switch_to_kernel();
+    assert!(space.translate(start).is_some());
+
+    assert!(vma::release(start).is_some());
+    drop(space);
+}
@@ -0,0 +1,26 @@
+#![no_std]
+#![no_main]
+
+use core::panic::PanicInfo;
+use rust_os::{exit_qemu, serial_print, serial_println, QemuExitCode};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    serial_print!("exception_panics::invalid_opcode...\t");
+    rust_os::init();
+
+    // #UD 的处理函数打印崩溃报告之后应该 panic，而不是停机
+    unsafe { core::arch::asm!("ud2") };
+
+    serial_println!("[failed]\n");
+    serial_println!("Error: execution continued after #UD\n");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
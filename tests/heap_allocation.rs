@@ -6,7 +6,7 @@
 
 extern crate alloc;
 
-use alloc::{boxed::Box, vec::Vec};
+use alloc::{boxed::Box, vec, vec::Vec};
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 
@@ -14,14 +14,16 @@ entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
     use rust_os::allocator;
-    use rust_os::memory::{self, BootInfoFrameAllocator};
+    use rust_os::memory::{self, BitmapFrameAllocator};
     use x86_64::VirtAddr;
 
     rust_os::init();
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
 
     test_main();
     loop {}
@@ -50,7 +52,7 @@ fn large_vec() {
     assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
 }
 
-use rust_os::allocator::HEAP_SIZE;
+use rust_os::allocator::{HEAP_SIZE, HEAP_START};
 #[test_case]
 fn many_boxes() {
     for i in 0..HEAP_SIZE {
@@ -59,6 +61,8 @@ fn many_boxes() {
     }
 }
 
+// a bump allocator can only reuse memory once every allocation is freed
+#[cfg(not(feature = "alloc-bump"))]
 #[test_case]
 fn many_boxes_long_lived() {
     let long_lived = Box::new(1); // new
@@ -68,3 +72,103 @@ fn many_boxes_long_lived() {
     }
     assert_eq!(*long_lived, 1); // new
 }
+
+// freed fixed-size blocks stay in their size class and never return to the
+// fallback allocator, so the heap cannot be reassembled; the debug allocator
+// adds red zones around the block
+#[cfg(not(any(feature = "alloc-fixed-size-block", feature = "alloc-debug")))]
+#[test_case]
+fn full_heap_after_fragmentation() {
+    // interleave differently sized allocations so that freeing them leaves
+    // holes all over the heap
+    let mut blocks: Vec<Option<Vec<u8>>> = (0..100)
+        .map(|i| Some(vec![i as u8; 16 * (i % 8 + 1)]))
+        .collect();
+    for block in blocks.iter_mut().step_by(2) {
+        *block = None;
+    }
+    for block in blocks.iter_mut().step_by(2) {
+        *block = Some(vec![0; 8]);
+    }
+    drop(blocks);
+
+    // only placed at the heap start if every freed region was merged back
+    // together, otherwise the heap would have to grow
+    let whole_heap = Vec::<u8>::with_capacity(HEAP_SIZE);
+    assert_eq!(whole_heap.as_ptr() as usize, HEAP_START);
+}
+
+#[test_case]
+fn allocation_beyond_initial_heap_size() {
+    let mut vec = Vec::<u8>::with_capacity(HEAP_SIZE * 2);
+    vec.resize(HEAP_SIZE * 2, 0xab);
+    assert!(vec.iter().all(|&b| b == 0xab));
+}
+
+#[test_case]
+fn heap_stats_balance_after_free() {
+    use rust_os::allocator::heap_stats;
+
+    let before = heap_stats();
+    {
+        let vec = vec![0u8; 4096];
+        let boxed = Box::new(42);
+        assert_eq!(heap_stats().bytes_in_use, before.bytes_in_use + 4096 + 4);
+        assert_eq!(vec.len() + *boxed, 4096 + 42);
+    }
+    let after = heap_stats();
+
+    assert_eq!(after.bytes_in_use, before.bytes_in_use);
+    assert_eq!(after.allocations - before.allocations, 2);
+    assert_eq!(after.deallocations - before.deallocations, 2);
+    assert!(after.peak_usage >= before.bytes_in_use + 4096);
+}
+
+#[test_case]
+fn realloc_is_not_counted_as_alloc_and_free() {
+    use rust_os::allocator::heap_stats;
+
+    let mut vec = Vec::<u8>::with_capacity(16);
+    let before = heap_stats();
+    vec.reserve_exact(64);
+    let after = heap_stats();
+
+    assert_eq!(
+        after.bytes_in_use,
+        before.bytes_in_use + vec.capacity() - 16
+    );
+    assert_eq!(after.allocations, before.allocations);
+    assert_eq!(after.deallocations, before.deallocations);
+}
+
+#[cfg(all(feature = "alloc-linked-list", not(feature = "alloc-debug")))]
+#[test_case]
+fn realloc_grows_and_shrinks_in_place() {
+    // everything after the vec is free, so it can grow into it
+    let mut vec = Vec::<u8>::with_capacity(64);
+    let ptr = vec.as_ptr();
+    vec.reserve_exact(1024);
+    assert_eq!(vec.as_ptr(), ptr);
+
+    vec.shrink_to(16);
+    assert_eq!(vec.as_ptr(), ptr);
+
+    // the tail returned by the shrink is reused right away
+    let next = Box::new([0u64; 4]);
+    assert_eq!(&*next as *const _ as usize, ptr as usize + 16);
+}
+
+#[cfg(all(feature = "alloc-linked-list", not(feature = "alloc-debug")))]
+#[test_case]
+fn realloc_moves_when_blocked() {
+    let mut vec: Vec<u64> = (0..8).collect();
+    vec.shrink_to_fit();
+    let ptr = vec.as_ptr();
+    // occupies the region directly after the vec
+    let blocker = Box::new([0u64; 8]);
+
+    vec.extend(8..64);
+    assert_ne!(vec.as_ptr(), ptr);
+    assert!(vec.iter().copied().eq(0..64));
+    assert_eq!(blocker.len(), 8);
+}
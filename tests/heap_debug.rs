@@ -0,0 +1,100 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::allocator::debug::{reported_errors, QUARANTINE_SIZE};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn clean_alloc_reports_nothing() {
+    let before = reported_errors();
+    let layout = Layout::new::<[u64; 4]>();
+    unsafe {
+        let ptr = alloc(layout);
+        ptr.write_bytes(0xff, layout.size());
+        dealloc(ptr, layout);
+    }
+    assert_eq!(reported_errors(), before);
+}
+
+#[test_case]
+fn write_after_free_is_detected() {
+    let layout = Layout::new::<[u8; 64]>();
+    unsafe {
+        let ptr = alloc(layout);
+        dealloc(ptr, layout);
+        // 块还在隔离区里，写入会破坏填充的 POISON_BYTE
+        ptr.add(8).write_volatile(0);
+        let before = reported_errors();
+        // 再释放 QUARANTINE_SIZE 个块，把它挤出隔离区
+        for _ in 0..QUARANTINE_SIZE {
+            dealloc(alloc(layout), layout);
+        }
+        assert_eq!(reported_errors(), before + 1);
+    }
+}
+
+#[test_case]
+fn double_free_is_detected() {
+    let layout = Layout::new::<u64>();
+    unsafe {
+        let ptr = alloc(layout);
+        dealloc(ptr, layout);
+        let before = reported_errors();
+        dealloc(ptr, layout);
+        assert_eq!(reported_errors(), before + 1);
+    }
+}
+
+#[test_case]
+fn overflow_is_detected() {
+    let layout = Layout::new::<[u8; 10]>();
+    unsafe {
+        let ptr = alloc(layout);
+        ptr.add(layout.size()).write(0);
+        let before = reported_errors();
+        dealloc(ptr, layout);
+        assert_eq!(reported_errors(), before + 1);
+    }
+}
+
+#[test_case]
+fn mismatched_layout_is_detected() {
+    let layout = Layout::new::<[u8; 32]>();
+    unsafe {
+        let ptr = alloc(layout);
+        let before = reported_errors();
+        dealloc(ptr, Layout::new::<[u8; 16]>());
+        assert_eq!(reported_errors(), before + 1);
+    }
+}
@@ -0,0 +1,84 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use rust_os::allocator::HEAP_START;
+use rust_os::memory::{self, mmio, paging};
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size2MiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+fn phys_mem_offset() -> VirtAddr {
+    VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::Relaxed))
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::BitmapFrameAllocator;
+
+    rust_os::init();
+    PHYS_MEM_OFFSET.store(boot_info.physical_memory_offset, Ordering::Relaxed);
+    let mapper = unsafe { memory::init(phys_mem_offset()) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset()) };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn huge_frame_is_aligned() {
+    memory::with_kernel_memory(|memory| {
+        let used = memory.frame_allocator.used_frames();
+        let frame: PhysFrame<Size2MiB> = memory.frame_allocator.allocate_frame().unwrap();
+        assert!(frame.start_address().is_aligned(Size2MiB::SIZE));
+        assert_eq!(memory.frame_allocator.used_frames(), used + 512);
+        unsafe { memory.frame_allocator.deallocate_frame(frame) };
+        assert_eq!(memory.frame_allocator.used_frames(), used);
+    })
+    .unwrap();
+}
+
+#[test_case]
+fn large_heap_growth_uses_huge_pages() {
+    // 4 MiB 的增长中至少有一个按 2 MiB 对齐的完整大页
+    let vec: Vec<u8> = Vec::with_capacity(4 * 1024 * 1024);
+    let start = vec.as_ptr() as u64;
+    let aligned = (start + Size2MiB::SIZE - 1) & !(Size2MiB::SIZE - 1);
+    assert!(aligned > HEAP_START as u64);
+
+    let translation = unsafe { paging::translate(VirtAddr::new(aligned), phys_mem_offset()) };
+    assert_eq!(translation.unwrap().page_size, Size2MiB::SIZE);
+}
+
+#[test_case]
+fn large_mmio_mapping_uses_huge_pages() {
+    let phys = PhysAddr::new(0x40_0000);
+    let virt = mmio::map_mmio(phys, 2 * Size2MiB::SIZE).unwrap();
+    assert!(virt.is_aligned(Size2MiB::SIZE));
+
+    let translation = unsafe { paging::translate(virt + 0x1234u64, phys_mem_offset()) }.unwrap();
+    assert_eq!(translation.page_size, Size2MiB::SIZE);
+    assert_eq!(translation.phys, phys + 0x1234u64);
+
+    mmio::unmap_mmio(virt).unwrap();
+    assert_eq!(unsafe { paging::translate(virt, phys_mem_offset()) }, None);
+}
@@ -0,0 +1,106 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use rust_os::interrupts::irq::{self, IrqError, IrqHandle, MAX_SHARED_HANDLERS};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    rust_os::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+static SHARED_TICKS: AtomicU64 = AtomicU64::new(0);
+
+fn shared_timer_handler() -> bool {
+    SHARED_TICKS.fetch_add(1, Ordering::Relaxed);
+    // 让内核自己的定时器处理函数认领这个中断
+    false
+}
+
+fn unused_handler() -> bool {
+    false
+}
+
+#[test_case]
+fn shared_handler_sees_timer_interrupts() {
+    let before = irq::count(irq::TIMER);
+    let handle = irq::register(irq::TIMER, &shared_timer_handler).unwrap();
+    for _ in 0..3 {
+        x86_64::instructions::hlt();
+    }
+    irq::unregister(handle);
+    let seen = SHARED_TICKS.load(Ordering::Relaxed);
+    assert!(seen > 0);
+    assert!(irq::count(irq::TIMER) >= before + seen);
+    assert_eq!(irq::unhandled(irq::TIMER), 0);
+
+    // 注销之后不再被调用
+    x86_64::instructions::hlt();
+    assert_eq!(SHARED_TICKS.load(Ordering::Relaxed), seen);
+}
+
+#[test_case]
+fn invalid_irq_is_rejected() {
+    assert_eq!(
+        irq::register(16, &unused_handler),
+        Err(IrqError::InvalidIrq)
+    );
+}
+
+#[test_case]
+fn shared_line_fills_up() {
+    const NONE: Option<IrqHandle> = None;
+    let mut handles = [NONE; MAX_SHARED_HANDLERS];
+    for handle in handles.iter_mut() {
+        *handle = Some(irq::register(irq::RTC, &unused_handler).unwrap());
+    }
+    assert!(irq::has_handlers(irq::RTC));
+    assert_eq!(
+        irq::register(irq::RTC, &unused_handler),
+        Err(IrqError::Full)
+    );
+    for handle in handles.iter_mut() {
+        irq::unregister(handle.take().unwrap());
+    }
+    assert!(!irq::has_handlers(irq::RTC));
+}
+
+fn pic_masks() -> [u8; 2] {
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        rust_os::interrupts::PICS.lock().read_masks()
+    })
+}
+
+#[test_case]
+fn registration_unmasks_the_pic_line() {
+    // 没有启用 APIC，中断线在 8259 中打开和屏蔽
+    let handle = irq::register(irq::RTC, &unused_handler).unwrap();
+    let [primary, secondary] = pic_masks();
+    assert_eq!(secondary & 1, 0);
+    // 级联线
+    assert_eq!(primary & (1 << 2), 0);
+    irq::unregister(handle);
+    assert_eq!(pic_masks()[1] & 1, 1);
+}
+
+#[test_case]
+fn unregistered_lines_are_masked() {
+    let [primary, secondary] = pic_masks();
+    let masks = u16::from(primary) | (u16::from(secondary) << 8);
+    // IRQ 2 是级联线，打开从片上的线时才打开
+    for line in (0..16).filter(|&line| line != 2) {
+        let masked = masks & (1 << line) != 0;
+        assert_eq!(masked, !irq::has_handlers(line), "IRQ {}", line);
+    }
+}
@@ -0,0 +1,95 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use rust_os::memory::mmio::{self, MmioError, MMIO_FLAGS, MMIO_START};
+use rust_os::memory::paging;
+use x86_64::{PhysAddr, VirtAddr};
+
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+fn phys_mem_offset() -> VirtAddr {
+    VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::Relaxed))
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::{self, BitmapFrameAllocator};
+
+    rust_os::init();
+    PHYS_MEM_OFFSET.store(boot_info.physical_memory_offset, Ordering::Relaxed);
+    let mapper = unsafe { memory::init(phys_mem_offset()) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset()) };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+// 屏幕最后一行，在 VGA 文本缓冲区里
+const VGA_LAST_ROW: u64 = 0xb8000 + 160 * 24;
+
+#[test_case]
+fn mmio_mapping_is_uncached() {
+    let virt = mmio::map_mmio(PhysAddr::new(VGA_LAST_ROW), 160).unwrap();
+    assert!(virt.as_u64() >= MMIO_START);
+    assert_eq!(virt.as_u64() % 4096, VGA_LAST_ROW % 4096);
+
+    let translation = unsafe { paging::translate(virt, phys_mem_offset()) }.unwrap();
+    assert_eq!(translation.phys, PhysAddr::new(VGA_LAST_ROW));
+    assert!(translation.flags.contains(MMIO_FLAGS));
+
+    // 写入的值通过 bootloader 的恒等映射可以读到
+    let ptr: *mut u16 = virt.as_mut_ptr();
+    let identity = VGA_LAST_ROW as *const u16;
+    unsafe {
+        ptr.write_volatile(0x0f21);
+        assert_eq!(identity.read_volatile(), 0x0f21);
+    }
+
+    mmio::unmap_mmio(virt).unwrap();
+    assert_eq!(unsafe { paging::translate(virt, phys_mem_offset()) }, None);
+}
+
+#[test_case]
+fn unmapped_range_is_reused() {
+    let first = mmio::map_mmio(PhysAddr::new(0xb8000), 3 * 4096).unwrap();
+    let second = mmio::map_mmio(PhysAddr::new(0xb8000), 4096).unwrap();
+    assert_eq!(second, first + 3 * 4096u64);
+
+    mmio::unmap_mmio(first).unwrap();
+    let third = mmio::map_mmio(PhysAddr::new(0xb8000), 2 * 4096).unwrap();
+    assert_eq!(third, first);
+
+    mmio::unmap_mmio(second).unwrap();
+    mmio::unmap_mmio(third).unwrap();
+    assert!(matches!(mmio::unmap_mmio(third), Err(MmioError::NotMapped)));
+}
+
+#[test_case]
+fn invalid_ranges_are_rejected() {
+    let phys = PhysAddr::new(0xb8000);
+    assert!(matches!(mmio::map_mmio(phys, 0), Err(MmioError::Empty)));
+    assert!(matches!(
+        mmio::map_mmio(phys, u64::MAX),
+        Err(MmioError::OutOfRange)
+    ));
+    assert!(matches!(
+        mmio::map_mmio(phys, mmio::MMIO_SIZE + 1),
+        Err(MmioError::WindowFull)
+    ));
+}
@@ -0,0 +1,72 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, Ordering};
+use rust_os::allocator::HEAP_START;
+use rust_os::memory::paging;
+use x86_64::{structures::paging::PageTableFlags, PhysAddr, VirtAddr};
+
+static PHYS_MEM_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+fn phys_mem_offset() -> VirtAddr {
+    VirtAddr::new(PHYS_MEM_OFFSET.load(Ordering::Relaxed))
+}
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    rust_os::init();
+    PHYS_MEM_OFFSET.store(boot_info.physical_memory_offset, Ordering::Relaxed);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn translate_vga_buffer() {
+    // the bootloader identity maps the VGA text buffer
+    let translation = unsafe { paging::translate(VirtAddr::new(0xb8123), phys_mem_offset()) };
+    let translation = translation.unwrap();
+    assert_eq!(translation.phys, PhysAddr::new(0xb8123));
+    assert!(translation.flags.contains(PageTableFlags::PRESENT));
+}
+
+#[test_case]
+fn translate_physical_memory_offset() {
+    let addr = phys_mem_offset() + 0x1000u64;
+    let translation = unsafe { paging::translate(addr, phys_mem_offset()) }.unwrap();
+    assert_eq!(translation.phys, PhysAddr::new(0x1000));
+}
+
+#[test_case]
+fn translate_unmapped() {
+    // the heap is not initialized in this test
+    let addr = VirtAddr::new(HEAP_START as u64);
+    assert_eq!(unsafe { paging::translate(addr, phys_mem_offset()) }, None);
+}
+
+#[test_case]
+fn walk_agrees_with_translate() {
+    let vga = 0xb8000;
+    let mut found = false;
+    unsafe {
+        paging::for_each_mapping(phys_mem_offset(), |mapping| {
+            let start = mapping.virt.as_u64();
+            if (start..start + mapping.page_size).contains(&vga) {
+                found = true;
+                assert_eq!(mapping.phys.as_u64() + (vga - start), vga);
+            }
+        });
+    }
+    assert!(found);
+}
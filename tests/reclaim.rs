@@ -0,0 +1,56 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::bootinfo::MemoryMap;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::memory::{self, reclaim};
+use spin::Mutex;
+use x86_64::VirtAddr;
+
+static MEMORY_MAP: Mutex<Option<&'static MemoryMap>> = Mutex::new(None);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::BitmapFrameAllocator;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+    *MEMORY_MAP.lock() = Some(&boot_info.memory_map);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn free_frames() -> usize {
+    memory::with_kernel_memory(|memory| memory.frame_allocator.free_frames()).unwrap()
+}
+
+#[test_case]
+fn boot_memory_is_reclaimed_once() {
+    let memory_map = MEMORY_MAP.lock().take().unwrap();
+    let free = free_frames();
+
+    // 第二次调用紧接着第一次，中间没有分配帧，内存 map 还没有被覆盖
+    let report = unsafe { reclaim::reclaim_boot_memory(memory_map) };
+    let again = unsafe { reclaim::reclaim_boot_memory(memory_map) };
+
+    assert!(report.bootloader > 0);
+    assert_eq!(free_frames(), free + (report.total() / 4096) as usize);
+    assert_eq!(again.total(), 0);
+}
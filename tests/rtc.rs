@@ -0,0 +1,101 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use rust_os::interrupts::irq::{self, IrqError, IrqHandle, MAX_SHARED_HANDLERS};
+use rust_os::time::{self, rtc, rtc::DateTime};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    rust_os::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn date(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+    DateTime {
+        year,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    }
+}
+
+#[test_case]
+fn unix_timestamps_round_trip() {
+    let cases = [
+        (0, date(1970, 1, 1, 0, 0, 0)),
+        (951_782_400, date(2000, 2, 29, 0, 0, 0)),
+        (1_700_000_000, date(2023, 11, 14, 22, 13, 20)),
+        (4_102_444_799, date(2099, 12, 31, 23, 59, 59)),
+    ];
+    for &(timestamp, date) in cases.iter() {
+        assert_eq!(date.unix_timestamp(), timestamp);
+        assert_eq!(DateTime::from_unix_timestamp(timestamp), date);
+    }
+}
+
+#[test_case]
+fn rtc_reads_a_plausible_date() {
+    let now = rtc::read();
+    assert!(now.year >= 2020);
+    assert!((1..=12).contains(&now.month));
+    assert!((1..=31).contains(&now.day));
+    assert!(now.hour < 24 && now.minute < 60 && now.second < 60);
+}
+
+#[test_case]
+fn wall_clock_follows_rtc() {
+    let synced = rtc::sync().unix_timestamp();
+    let now = rtc::now();
+    assert!(now.as_secs() >= synced);
+    assert!(now.as_secs() <= synced + 1);
+
+    let ticks = time::ticks();
+    while time::ticks() < ticks + 5 {
+        x86_64::instructions::hlt();
+    }
+    assert!(rtc::now() > now);
+}
+
+#[test_case]
+fn periodic_interrupts_arrive_on_irq_8() {
+    assert_eq!(rtc::enable_periodic(1000), Ok(512));
+    assert_eq!(rtc::enable_periodic(1024), Ok(1024));
+    let ticks = rtc::periodic_ticks();
+    let count = irq::count(irq::RTC);
+    while rtc::periodic_ticks() < ticks + 3 {
+        x86_64::instructions::hlt();
+    }
+    rtc::disable_periodic();
+    assert!(irq::count(irq::RTC) >= count + 3);
+    assert!(!irq::has_handlers(irq::RTC));
+}
+
+fn unused_handler() -> bool {
+    false
+}
+
+#[test_case]
+fn enabling_periodic_interrupts_on_a_full_line_fails() {
+    const NONE: Option<IrqHandle> = None;
+    let mut handles = [NONE; MAX_SHARED_HANDLERS];
+    for handle in handles.iter_mut() {
+        *handle = Some(irq::register(irq::RTC, &unused_handler).unwrap());
+    }
+    assert_eq!(rtc::enable_periodic(1024), Err(IrqError::Full));
+    for handle in handles.iter_mut() {
+        irq::unregister(handle.take().unwrap());
+    }
+    rtc::disable_periodic();
+}
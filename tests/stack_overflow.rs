@@ -2,16 +2,33 @@
 #![no_main]
 #![feature(abi_x86_interrupt)]
 
+use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
+use rust_os::memory::{self, paging, BitmapFrameAllocator};
 use rust_os::serial_print;
+use x86_64::VirtAddr;
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
     serial_print!("stack_overflow::stack_overflow...\t");
 
     rust_os::gdt::init();
     init_test_idt();
 
+    // the double fault handler runs on a stack with a guard page below it
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    let stack = rust_os::gdt::init_guarded_stacks().expect("kernel stack allocation failed");
+
+    // the stack is mapped, the page below it is not
+    let guard = stack.guard_page().start_address();
+    assert!(unsafe { paging::translate(stack.top - 1u64, phys_mem_offset) }.is_some());
+    assert!(unsafe { paging::translate(guard, phys_mem_offset) }.is_none());
+
     // trigger a stack overflow
     stack_overflow();
 
@@ -40,8 +57,17 @@ extern "x86-interrupt" fn test_double_fault_handler(
     _stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
-    serial_println!("[ok]");
-    exit_qemu(QemuExitCode::Success);
+    let rsp: u64;
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp) };
+    let base = memory::stack::KERNEL_STACKS_START;
+    if (base..base + memory::stack::KERNEL_STACKS_SIZE).contains(&rsp) {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: double fault handler ran on stack {:#x}\n", rsp);
+        exit_qemu(QemuExitCode::Failed);
+    }
     loop {}
 }
 
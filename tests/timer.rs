@@ -0,0 +1,64 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use core::panic::PanicInfo;
+use rust_os::time::{self, pit};
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    rust_os::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn default_frequency_is_programmed() {
+    assert_eq!(time::tick_frequency(), time::DEFAULT_TICK_FREQUENCY);
+}
+
+#[test_case]
+fn frequency_is_clamped_to_pit_range() {
+    assert_eq!(pit::set_frequency(1), 18);
+    assert_eq!(pit::set_frequency(u32::MAX), 596_591);
+    // 实际频率四舍五入: 1193 分频是 1000.15 Hz
+    assert_eq!(pit::set_frequency(1000), 1000);
+    assert_eq!(
+        time::set_tick_frequency(time::DEFAULT_TICK_FREQUENCY),
+        time::DEFAULT_TICK_FREQUENCY
+    );
+}
+
+#[test_case]
+fn ticks_and_uptime_advance() {
+    let ticks = time::ticks();
+    let uptime = time::uptime();
+    for _ in 0..5 {
+        x86_64::instructions::hlt();
+    }
+    assert!(time::ticks() > ticks);
+    assert!(time::uptime() > uptime);
+}
+
+#[test_case]
+fn uptime_follows_frequency_changes() {
+    time::set_tick_frequency(1000);
+    let start_ticks = time::ticks();
+    let start = time::uptime();
+    while time::ticks() < start_ticks + 20 {
+        x86_64::instructions::hlt();
+    }
+    let elapsed = time::uptime() - start;
+    time::set_tick_frequency(time::DEFAULT_TICK_FREQUENCY);
+    // 1000 Hz 下每个 tick 约 1 ms
+    let ticks = (time::ticks() - start_ticks) as u128;
+    assert!(elapsed.as_micros() >= (ticks - 1) * 999);
+    assert!(elapsed.as_micros() <= (ticks + 1) * 1001);
+}
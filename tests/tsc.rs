@@ -0,0 +1,80 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::time::Duration;
+use rust_os::memory;
+use rust_os::time::{self, tsc, Instant};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_os::allocator;
+    use rust_os::memory::BitmapFrameAllocator;
+    use x86_64::VirtAddr;
+
+    rust_os::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_map, phys_mem_offset) };
+    memory::init_kernel_memory(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+#[test_case]
+fn hpet_and_pit_calibrations_agree() {
+    let calibration = tsc::calibrate();
+    assert!(calibration.frequency > 100_000_000);
+    // 有没有 HPET 取决于 QEMU 的机器类型，没有的话已经是用 PIT 校准的
+    if calibration.reference == tsc::Reference::Hpet {
+        // 用 PIT 再测一次，误差应该在 5% 以内
+        let pit = tsc::measure_with_pit();
+        let diff = calibration.frequency.abs_diff(pit);
+        assert!(diff < calibration.frequency / 20);
+    }
+}
+
+#[test_case]
+fn instants_are_monotonic() {
+    let earlier = Instant::now();
+    let later = Instant::now();
+    assert!(later >= earlier);
+    assert_eq!(earlier.duration_since(later), Duration::ZERO);
+    assert_eq!(earlier + Duration::ZERO, earlier);
+    // 换算成 TSC 周期时溢出
+    assert_eq!(earlier.checked_add(Duration::MAX), None);
+}
+
+#[test_case]
+fn delay_waits_at_least_the_duration() {
+    let start = Instant::now();
+    time::delay(Duration::from_millis(5));
+    assert!(start.elapsed() >= Duration::from_millis(5));
+}
+
+#[test_case]
+fn instant_agrees_with_tick_clock() {
+    let start = Instant::now();
+    let ticks = time::ticks();
+    while time::ticks() < ticks + 10 {
+        x86_64::instructions::hlt();
+    }
+    let elapsed = start.elapsed();
+    // 100 Hz 下 10 个 tick 大约 100 ms，允许一个 tick 的误差和一些抖动
+    let period = Duration::from_millis(1000 / time::DEFAULT_TICK_FREQUENCY as u64);
+    assert!(elapsed >= period * 9);
+    assert!(elapsed <= period * 12);
+}